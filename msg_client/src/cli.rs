@@ -0,0 +1,51 @@
+use clap::Parser;
+
+use msg_client::Identity;
+
+/// A terminal client for msg_server.
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Args {
+    /// Server to connect to, as `host:port`.
+    #[arg(default_value = "127.0.0.1:8080")]
+    pub server: String,
+
+    /// Nickname to use on servers without accounts.
+    #[arg(short, long, env = "USER")]
+    pub nick: Option<String>,
+
+    /// Log in to this account instead of choosing a nickname.
+    #[arg(short, long, value_name = "NAME", requires = "password")]
+    pub user: Option<String>,
+
+    /// Password for `--user`.
+    #[arg(long, env = "MSG_CLIENT_PASSWORD", hide_env_values = true)]
+    pub password: Option<String>,
+
+    /// Create the `--user` account first.
+    #[arg(long, requires = "user")]
+    pub register: bool,
+
+    /// Room to join once connected, besides the server's default room.
+    #[arg(short, long, value_name = "ROOM")]
+    pub join: Vec<String>,
+}
+
+impl Args {
+    /// How to identify to the server, or `None` if neither a nickname nor
+    /// an account was given.
+    pub fn identity(&self) -> Option<Identity> {
+        match (&self.user, &self.password, &self.nick) {
+            (Some(user), Some(password), _) => {
+                let (user, password) = (user.clone(), password.clone());
+                Some(if self.register {
+                    Identity::Register { user, password }
+                } else {
+                    Identity::Login { user, password }
+                })
+            }
+            (None, _, Some(nick)) => Some(Identity::Nick(nick.clone())),
+            _ => None,
+        }
+    }
+}
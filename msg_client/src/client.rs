@@ -0,0 +1,341 @@
+use std::{
+    ops::Deref,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::Stream;
+use msg_server::protocol::{self, Message, ProtocolError, Since, LEN_PREFIX, VERSION};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::{TcpStream, ToSocketAddrs},
+    sync::mpsc,
+};
+
+use crate::error::ClientError;
+
+/// Longest frame accepted from the server. Generous, since the server's own
+/// limit is configurable.
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// Messages queued in each direction before senders wait.
+const QUEUE_LEN: usize = 64;
+
+/// How a client identifies itself when it connects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Identity {
+    /// Claim a nickname, on servers without accounts.
+    Nick(String),
+    /// Log in to an existing account.
+    Login { user: String, password: String },
+    /// Create an account and log in to it.
+    Register { user: String, password: String },
+}
+
+impl From<Identity> for Message {
+    fn from(identity: Identity) -> Self {
+        match identity {
+            Identity::Nick(nick) => Message::Nick { nick, old: None },
+            Identity::Login { user, password } => Message::Login { user, password },
+            Identity::Register { user, password } => Message::Register { user, password },
+        }
+    }
+}
+
+/// A registered connection to the server.
+///
+/// Messages from the server are read with [`next`](Self::next) or by using
+/// the client as a [`Stream`]. Sending goes through the [`Sender`] the
+/// client derefs to, which can also be cloned for other tasks.
+#[derive(Debug)]
+pub struct Client {
+    sender: Sender,
+    incoming: mpsc::Receiver<Result<Message, ClientError>>,
+    nick: String,
+    /// The rooms joined, in the order they were joined.
+    rooms: Vec<String>,
+}
+
+impl Client {
+    /// Connects over TCP and registers as `identity`.
+    pub async fn connect(
+        addr: impl ToSocketAddrs,
+        identity: Identity,
+    ) -> Result<Client, ClientError> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Client::handshake(stream, identity).await
+    }
+
+    /// Registers as `identity` over an already open stream, such as a TLS
+    /// connection.
+    pub async fn handshake<S>(stream: S, identity: Identity) -> Result<Client, ClientError>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut read, mut write) = io::split(stream);
+        write.write_all(&protocol::preamble()).await?;
+        write.write_all(&protocol::encode(&identity.into())).await?;
+
+        // The server greets, prompts and then confirms the nickname. An
+        // error at any point means the registration failed.
+        let nick = loop {
+            match read_frame(&mut read).await? {
+                Message::Hello { version } if version != VERSION => {
+                    return Err(ProtocolError::UnsupportedVersion(version).into())
+                }
+                Message::Nick { nick, old: None } => break nick,
+                Message::Error { text, .. } => return Err(ClientError::Refused(text)),
+                Message::Ping { token } => {
+                    write
+                        .write_all(&protocol::encode(&Message::Pong { token }))
+                        .await?
+                }
+                _ => {}
+            }
+        };
+
+        let (outgoing, queued) = mpsc::channel(QUEUE_LEN);
+        let (received, incoming) = mpsc::channel(QUEUE_LEN);
+        tokio::spawn(write_loop(write, queued));
+        tokio::spawn(read_loop(read, received, outgoing.downgrade()));
+        Ok(Client {
+            sender: Sender { outgoing },
+            incoming,
+            nick,
+            rooms: Vec::new(),
+        })
+    }
+
+    /// The nickname the server knows this client by.
+    pub fn nick(&self) -> &str {
+        &self.nick
+    }
+
+    /// The rooms joined, in the order they were joined.
+    pub fn rooms(&self) -> &[String] {
+        &self.rooms
+    }
+
+    /// A handle for sending from other tasks.
+    pub fn sender(&self) -> Sender {
+        self.sender.clone()
+    }
+
+    /// Waits for the next message from the server. Returns `None` once the
+    /// connection is closed.
+    pub async fn next(&mut self) -> Option<Result<Message, ClientError>> {
+        let next = self.incoming.recv().await;
+        self.track(next)
+    }
+
+    /// Tells the server this client is leaving and waits for it to close
+    /// the connection.
+    pub async fn quit(mut self, reason: Option<&str>) -> Result<(), ClientError> {
+        self.sender
+            .send(Message::Quit {
+                nick: None,
+                reason: reason.map(str::to_string),
+            })
+            .await?;
+        while let Some(msg) = self.next().await {
+            msg?;
+        }
+        Ok(())
+    }
+
+    /// Keeps the nickname and room list in step with what the server says
+    /// about this client.
+    fn track(
+        &mut self,
+        next: Option<Result<Message, ClientError>>,
+    ) -> Option<Result<Message, ClientError>> {
+        match &next {
+            Some(Ok(Message::Nick { nick, old: None })) => self.nick.clone_from(nick),
+            Some(Ok(Message::Join {
+                room, nick: None, ..
+            })) if !self.rooms.contains(room) => self.rooms.push(room.clone()),
+            Some(Ok(Message::Leave {
+                room, nick: None, ..
+            })) => self.rooms.retain(|joined| joined != room),
+            _ => {}
+        }
+        next
+    }
+}
+
+impl Deref for Client {
+    type Target = Sender;
+
+    fn deref(&self) -> &Sender {
+        &self.sender
+    }
+}
+
+impl Stream for Client {
+    type Item = Result<Message, ClientError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let next = std::task::ready!(self.incoming.poll_recv(cx));
+        Poll::Ready(self.track(next))
+    }
+}
+
+/// Sends messages and commands to the server. Every method fails with
+/// [`ClientError::Closed`] once the connection is gone.
+#[derive(Debug, Clone)]
+pub struct Sender {
+    outgoing: mpsc::Sender<Message>,
+}
+
+impl Sender {
+    pub async fn send(&self, msg: Message) -> Result<(), ClientError> {
+        self.outgoing
+            .send(msg)
+            .await
+            .map_err(|_| ClientError::Closed)
+    }
+
+    /// Says `text` in the room the server considers this client to be
+    /// talking in. A leading `/` is sent as text, not run as a command.
+    pub async fn say(&self, text: &str) -> Result<(), ClientError> {
+        let text = if text.starts_with('/') {
+            format!("/{text}")
+        } else {
+            text.to_string()
+        };
+        self.send(Message::chat(text)).await
+    }
+
+    /// Says `text` in `room`, which must have been joined.
+    pub async fn say_in(&self, room: &str, text: &str) -> Result<(), ClientError> {
+        self.send(Message::Chat {
+            id: None,
+            room: Some(room.to_string()),
+            from: None,
+            to: None,
+            text: text.to_string(),
+            ts: None,
+        })
+        .await
+    }
+
+    /// Sends `text` privately to the client called `to`.
+    pub async fn msg(&self, to: &str, text: &str) -> Result<(), ClientError> {
+        self.send(Message::Chat {
+            id: None,
+            room: None,
+            from: None,
+            to: Some(to.to_string()),
+            text: text.to_string(),
+            ts: None,
+        })
+        .await
+    }
+
+    /// Joins `room`, or makes it the room plain text goes to if it is
+    /// already joined.
+    pub async fn join(&self, room: &str) -> Result<(), ClientError> {
+        self.join_since(room, None).await
+    }
+
+    /// Joins `room`, replaying its history after `since`.
+    pub async fn join_since(&self, room: &str, since: Option<Since>) -> Result<(), ClientError> {
+        self.send(Message::Join {
+            room: room.to_string(),
+            nick: None,
+            since,
+        })
+        .await
+    }
+
+    pub async fn part(&self, room: &str) -> Result<(), ClientError> {
+        self.send(Message::Leave {
+            room: room.to_string(),
+            nick: None,
+            reason: None,
+        })
+        .await
+    }
+
+    /// Asks to be called `nick` from now on.
+    pub async fn rename(&self, nick: &str) -> Result<(), ClientError> {
+        self.send(Message::Nick {
+            nick: nick.to_string(),
+            old: None,
+        })
+        .await
+    }
+
+    /// Runs a slash command such as `/who` or `/kick bob spam`. The leading
+    /// `/` is optional. Replies arrive as system or error messages.
+    pub async fn command(&self, line: &str) -> Result<(), ClientError> {
+        let line = line.strip_prefix('/').unwrap_or(line);
+        self.send(Message::chat(format!("/{line}"))).await
+    }
+}
+
+async fn read_frame<R>(read: &mut R) -> Result<Message, ClientError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut prefix = [0; LEN_PREFIX];
+    match read.read_exact(&mut prefix).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Err(ClientError::Closed),
+        Err(err) => return Err(err.into()),
+    }
+    let len = u32::from_be_bytes(prefix) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(ProtocolError::FrameTooLarge {
+            len,
+            max: MAX_FRAME_LEN,
+        }
+        .into());
+    }
+    let mut body = vec![0; len];
+    read.read_exact(&mut body).await?;
+    Ok(serde_json::from_slice(&body).map_err(ProtocolError::from)?)
+}
+
+/// Hands messages to the client until the connection closes, answering
+/// pings on the way.
+async fn read_loop<S>(
+    mut read: ReadHalf<S>,
+    received: mpsc::Sender<Result<Message, ClientError>>,
+    outgoing: mpsc::WeakSender<Message>,
+) where
+    S: AsyncRead,
+{
+    loop {
+        let msg = match read_frame(&mut read).await {
+            Ok(Message::Ping { token }) => {
+                if let Some(outgoing) = outgoing.upgrade() {
+                    let _ = outgoing.send(Message::Pong { token }).await;
+                }
+                continue;
+            }
+            Ok(msg) => Ok(msg),
+            Err(ClientError::Closed) => return,
+            Err(err) => Err(err),
+        };
+        let failed = msg.is_err();
+        if received.send(msg).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Writes queued messages until every [`Sender`] is dropped, then closes
+/// the connection.
+async fn write_loop<S>(mut write: WriteHalf<S>, mut queued: mpsc::Receiver<Message>)
+where
+    S: AsyncWrite,
+{
+    while let Some(msg) = queued.recv().await {
+        if write.write_all(&protocol::encode(&msg)).await.is_err() {
+            return;
+        }
+    }
+    let _ = write.shutdown().await;
+}
@@ -0,0 +1,17 @@
+use std::io;
+
+use msg_server::protocol::ProtocolError;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    /// The server turned the connection or the registration down.
+    #[error("refused by the server: {0}")]
+    Refused(String),
+    #[error("connection closed")]
+    Closed,
+}
@@ -0,0 +1,28 @@
+//! A client for msg_server's framed protocol.
+//!
+//! [`Client`] connects, registers a nickname or logs in, and then sends
+//! messages and commands while handing everything the server sends back
+//! out as a stream of [`Message`]s. Pings are answered automatically.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), msg_client::ClientError> {
+//! use msg_client::{Client, Identity};
+//!
+//! let mut client = Client::connect("127.0.0.1:8080", Identity::Nick("alice".into())).await?;
+//! client.join("#rust").await?;
+//! client.say_in("#rust", "hello").await?;
+//! while let Some(msg) = client.next().await {
+//!     if let Some(line) = msg?.to_text() {
+//!         println!("{line}");
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+mod client;
+mod error;
+
+pub use client::{Client, Identity, Sender, MAX_FRAME_LEN};
+pub use error::ClientError;
+pub use msg_server::protocol::{Message, Since};
@@ -0,0 +1,90 @@
+mod cli;
+mod ui;
+
+use std::process::ExitCode;
+
+use clap::{error::ErrorKind, CommandFactory, Parser};
+use crossterm::event::{Event, EventStream, KeyEventKind};
+use futures_util::StreamExt;
+use msg_client::{Client, ClientError};
+use ratatui::DefaultTerminal;
+
+use ui::{App, Flow};
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run() -> Result<(), ClientError> {
+    let args = cli::Args::parse();
+    let Some(identity) = args.identity() else {
+        cli::Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "give a nickname with --nick, or an account with --user",
+            )
+            .exit();
+    };
+    let mut client = Client::connect(&args.server, identity).await?;
+    for room in &args.join {
+        client.join(room).await?;
+    }
+
+    let mut app = App::new();
+    app.notice(&format!(
+        "connected to {} as {}; {}",
+        args.server,
+        client.nick(),
+        ui::KEYS
+    ));
+    let mut terminal = ratatui::init();
+    let flow = interact(&mut terminal, &mut client, &mut app).await;
+    ratatui::restore();
+
+    match flow? {
+        Flow::Quit(reason) => client.quit(reason.as_deref()).await,
+        Flow::Continue => {
+            match app.last_error() {
+                Some(text) => eprintln!("disconnected: {text}"),
+                None => eprintln!("disconnected"),
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Runs the interface until the user quits, returning [`Flow::Quit`], or
+/// the server closes the connection, returning [`Flow::Continue`].
+async fn interact(
+    terminal: &mut DefaultTerminal,
+    client: &mut Client,
+    app: &mut App,
+) -> Result<Flow, ClientError> {
+    let mut events = EventStream::new();
+    loop {
+        terminal.draw(|frame| app.draw(frame, client))?;
+        tokio::select! {
+            msg = client.next() => match msg {
+                Some(msg) => app.receive(&msg?, client),
+                None => return Ok(Flow::Continue),
+            },
+            event = events.next() => match event {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                    if let Flow::Quit(reason) = app.key(key, client).await? {
+                        return Ok(Flow::Quit(reason));
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(err.into()),
+                None => return Ok(Flow::Quit(None)),
+            },
+        }
+    }
+}
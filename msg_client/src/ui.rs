@@ -0,0 +1,477 @@
+//! The terminal interface: the scrollback, the list of joined rooms and an
+//! input line showing the nickname and the room being talked in.
+
+use std::collections::VecDeque;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use msg_client::{Client, ClientError, Message};
+use msg_server::Command;
+use ratatui::{
+    layout::{Constraint, Layout, Position},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, List, ListItem, ListState, Paragraph, Wrap},
+    Frame,
+};
+
+/// Lines kept in the scrollback.
+const SCROLLBACK: usize = 5000;
+
+/// Rows moved by Page Up and Page Down.
+const PAGE: usize = 10;
+
+const ROOM_LIST_WIDTH: u16 = 20;
+
+/// Our own chat lines: waiting for the server, accepted and refused.
+const PENDING: Style = Style::new().fg(Color::DarkGray).add_modifier(Modifier::DIM);
+const SENT: Style = Style::new().fg(Color::Cyan);
+const FAILED: Style = Style::new()
+    .fg(Color::Red)
+    .add_modifier(Modifier::CROSSED_OUT);
+
+pub const KEYS: &str = "Tab switches rooms, PgUp/PgDn scroll, Esc quits";
+
+/// What the event loop should do after a key press.
+pub enum Flow {
+    Continue,
+    /// Leave, with the reason given.
+    Quit(Option<String>),
+}
+
+/// A message sent to the server and not yet acknowledged.
+struct Pending {
+    id: u64,
+    /// The scrollback line showing it, for chat lines.
+    line: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct App {
+    lines: VecDeque<Line<'static>>,
+    /// The number of lines dropped from the front of the scrollback, so
+    /// that `lines[n]` is line `dropped + n`.
+    dropped: u64,
+    input: String,
+    /// Byte offset of the cursor in `input`.
+    cursor: usize,
+    /// Rows scrolled back from the newest line.
+    scroll: usize,
+    /// The joined room typed text goes to.
+    room: Option<String>,
+    /// The last error from the server, shown when it disconnects us.
+    last_error: Option<String>,
+    /// The id for the next message sent.
+    next_id: u64,
+    /// Messages sent and not yet answered, oldest first.
+    pending: VecDeque<Pending>,
+}
+
+impl App {
+    pub fn new() -> Self {
+        App::default()
+    }
+
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    /// Shows a line of the client's own.
+    pub fn notice(&mut self, text: &str) {
+        self.show(Line::styled(format!("*** {text}"), Color::DarkGray));
+    }
+
+    /// Shows a message from the server. `client` has already seen it, so
+    /// its room list is up to date.
+    pub fn receive(&mut self, msg: &Message, client: &Client) {
+        match msg {
+            Message::Join {
+                room, nick: None, ..
+            } => self.room = Some(room.clone()),
+            Message::Leave {
+                room, nick: None, ..
+            } if self.room.as_ref() == Some(room) => {
+                self.room = client.rooms().first().cloned();
+            }
+            Message::Error { text, .. } => self.last_error = Some(text.clone()),
+            _ => {}
+        }
+        self.settle(msg);
+        let Some(text) = msg.to_text() else {
+            return;
+        };
+        let style = match msg {
+            Message::Chat { to: Some(_), .. } => Style::new().fg(Color::Magenta),
+            Message::Chat { text, .. } if mentions(text, client.nick()) => {
+                Style::new().fg(Color::Yellow).add_modifier(Modifier::BOLD)
+            }
+            Message::Chat { .. } => Style::new(),
+            Message::Error { .. } => Style::new().fg(Color::Red),
+            _ => Style::new().fg(Color::DarkGray),
+        };
+        self.show(Line::styled(text, style));
+    }
+
+    /// Marks our own lines as sent or refused once the server answers them.
+    /// Errors about a message carry its id; errors without one, such as
+    /// being muted by an operator, answer nothing.
+    fn settle(&mut self, msg: &Message) {
+        match *msg {
+            Message::Error { id: Some(id), .. } => {
+                if let Some(at) = self.pending.iter().position(|sent| sent.id == id) {
+                    if let Some(line) = self.pending.remove(at).and_then(|sent| sent.line) {
+                        self.restyle(line, FAILED);
+                    }
+                }
+            }
+            // The server answers messages in order, so anything older that
+            // is still pending was dropped without an id, for being too
+            // long. Messages that failed were already taken off by their
+            // error.
+            Message::Ack { id } => {
+                while let Some(sent) = self.pending.pop_front_if(|sent| sent.id <= id) {
+                    if let Some(line) = sent.line {
+                        self.restyle(line, if sent.id == id { SENT } else { FAILED });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Adds a line to the scrollback and returns its number.
+    fn show(&mut self, line: Line<'static>) -> u64 {
+        if self.lines.len() == SCROLLBACK {
+            self.lines.pop_front();
+            self.dropped += 1;
+        }
+        self.lines.push_back(line);
+        // Keep what is on screen in place while scrolled back.
+        if self.scroll > 0 {
+            self.scroll += 1;
+        }
+        self.dropped + self.lines.len() as u64 - 1
+    }
+
+    /// Changes the style of line number `line`, unless it has scrolled off.
+    fn restyle(&mut self, line: u64, style: Style) {
+        let Some(at) = line.checked_sub(self.dropped) else {
+            return;
+        };
+        if let Some(line) = self.lines.get_mut(at as usize) {
+            line.style = style;
+        }
+    }
+
+    /// Sends a chat line or command to the server with a fresh id, so that
+    /// its outcome can be told apart from the others'.
+    async fn request(
+        &mut self,
+        room: Option<String>,
+        text: String,
+        line: Option<u64>,
+        client: &Client,
+    ) -> Result<(), ClientError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        client
+            .send(Message::Chat {
+                id: Some(id),
+                room,
+                from: None,
+                to: None,
+                text,
+                ts: None,
+            })
+            .await?;
+        self.pending.push_back(Pending { id, line });
+        Ok(())
+    }
+
+    pub async fn key(&mut self, key: KeyEvent, client: &Client) -> Result<Flow, ClientError> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Esc => return Ok(Flow::Quit(None)),
+            KeyCode::Char('c') if ctrl => return Ok(Flow::Quit(None)),
+            KeyCode::Char('d') if ctrl && self.input.is_empty() => return Ok(Flow::Quit(None)),
+            KeyCode::Enter => {
+                let line = std::mem::take(&mut self.input);
+                self.cursor = 0;
+                self.scroll = 0;
+                return self.submit(&line, client).await;
+            }
+            KeyCode::Tab => self.cycle(client, 1),
+            KeyCode::BackTab => self.cycle(client, -1),
+            KeyCode::PageUp => {
+                self.scroll = (self.scroll + PAGE).min(self.lines.len().saturating_sub(1))
+            }
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(PAGE),
+            KeyCode::Left => self.cursor = self.previous(),
+            KeyCode::Right => self.cursor = self.next(),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::Char('a') if ctrl => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.len(),
+            KeyCode::Char('e') if ctrl => self.cursor = self.input.len(),
+            KeyCode::Char('u') if ctrl => {
+                self.input.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                let start = self.previous();
+                self.input.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            KeyCode::Delete if self.cursor < self.input.len() => {
+                let end = self.next();
+                self.input.drain(self.cursor..end);
+            }
+            KeyCode::Char(c) if !ctrl => {
+                self.input.insert(self.cursor, c);
+                self.cursor += c.len_utf8();
+            }
+            _ => {}
+        }
+        Ok(Flow::Continue)
+    }
+
+    /// Sends a line typed by the user. Commands go to the server, except
+    /// that `/quit` ends the session and commands defaulting to the active
+    /// room are pointed at the room selected here.
+    async fn submit(&mut self, line: &str, client: &Client) -> Result<Flow, ClientError> {
+        let line = line.trim_end();
+        if line.trim().is_empty() {
+            return Ok(Flow::Continue);
+        }
+        if let Some(command) = line.strip_prefix('/').filter(|rest| !rest.starts_with('/')) {
+            match (command.parse(), &self.room) {
+                (Ok(Command::Quit(reason)), _) => return Ok(Flow::Quit(reason)),
+                (Ok(Command::Join { room, .. }), _) => {
+                    if let Some(joined) = client
+                        .rooms()
+                        .iter()
+                        .find(|joined| same_room(joined, &room))
+                    {
+                        self.room = Some(joined.clone());
+                    }
+                }
+                (Ok(Command::Part(None)), Some(room)) => {
+                    let text = format!("/part {room}");
+                    return self
+                        .request(None, text, None, client)
+                        .await
+                        .map(|()| Flow::Continue);
+                }
+                (Ok(Command::Names(None)), Some(room)) => {
+                    let text = format!("/names {room}");
+                    return self
+                        .request(None, text, None, client)
+                        .await
+                        .map(|()| Flow::Continue);
+                }
+                _ => {}
+            }
+            self.request(None, format!("/{command}"), None, client)
+                .await?;
+            return Ok(Flow::Continue);
+        }
+
+        // `//text` says `/text`.
+        let text = line.strip_prefix('/').unwrap_or(line);
+        let Some(room) = self.room.clone() else {
+            self.notice("you are not in a room; /join one first");
+            return Ok(Flow::Continue);
+        };
+        // The server doesn't echo our own messages. The line is dimmed until
+        // the server acknowledges it.
+        let echo = Message::Chat {
+            id: None,
+            room: Some(room.clone()),
+            from: Some(client.nick().to_string()),
+            to: None,
+            text: text.to_string(),
+            ts: None,
+        };
+        let line = echo
+            .to_text()
+            .map(|echo| self.show(Line::styled(echo, PENDING)));
+        self.request(Some(room), text.to_string(), line, client)
+            .await?;
+        Ok(Flow::Continue)
+    }
+
+    /// Selects the next or previous joined room.
+    fn cycle(&mut self, client: &Client, step: isize) {
+        let rooms = client.rooms();
+        if rooms.is_empty() {
+            return;
+        }
+        let at = self
+            .room
+            .as_ref()
+            .and_then(|room| rooms.iter().position(|joined| joined == room))
+            .unwrap_or(0) as isize;
+        let next = (at + step).rem_euclid(rooms.len() as isize) as usize;
+        self.room = Some(rooms[next].clone());
+    }
+
+    fn previous(&self) -> usize {
+        self.input[..self.cursor]
+            .char_indices()
+            .next_back()
+            .map_or(0, |(at, _)| at)
+    }
+
+    fn next(&self) -> usize {
+        self.input[self.cursor..]
+            .chars()
+            .next()
+            .map_or(self.cursor, |c| self.cursor + c.len_utf8())
+    }
+
+    pub fn draw(&self, frame: &mut Frame<'_>, client: &Client) {
+        let [main, input] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(3)]).areas(frame.area());
+        let [scrollback, rooms] =
+            Layout::horizontal([Constraint::Min(1), Constraint::Length(ROOM_LIST_WIDTH)])
+                .areas(main);
+
+        let mut block = Block::bordered().title(" msg_client ");
+        if self.scroll > 0 {
+            block =
+                block.title_bottom(Line::from(format!(" {} more ", self.scroll)).right_aligned());
+        }
+        let inner = block.inner(scrollback);
+        frame.render_widget(block, scrollback);
+        frame.render_widget(self.scrollback(inner.width, inner.height as usize), inner);
+
+        let items: Vec<_> = client
+            .rooms()
+            .iter()
+            .map(|room| ListItem::new(room.as_str()))
+            .collect();
+        let mut state = ListState::default().with_selected(
+            self.room
+                .as_ref()
+                .and_then(|room| client.rooms().iter().position(|joined| joined == room)),
+        );
+        let list = List::new(items)
+            .block(Block::bordered().title(" rooms "))
+            .highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(list, rooms, &mut state);
+
+        let mut title = vec![Span::raw(" "), client.nick().to_string().bold()];
+        if let Some(room) = &self.room {
+            title.push(Span::raw(format!(" in {room}")));
+        }
+        title.push(Span::raw(" "));
+        let block = Block::bordered().title(Line::from(title));
+        let inner = block.inner(input);
+        let column = Span::raw(&self.input[..self.cursor]).width() as u16;
+        let offset = column.saturating_sub(inner.width.saturating_sub(1));
+        frame.render_widget(
+            Paragraph::new(self.input.as_str())
+                .block(block)
+                .scroll((0, offset)),
+            input,
+        );
+        frame.set_cursor_position(Position::new(inner.x + column - offset, inner.y));
+    }
+
+    /// The newest lines that fill `height` rows of `width` columns, after
+    /// skipping the rows scrolled back.
+    fn scrollback(&self, width: u16, height: usize) -> Paragraph<'static> {
+        let wanted = height + self.scroll;
+        let mut rows = 0;
+        let mut start = self.lines.len();
+        while start > 0 && rows < wanted {
+            start -= 1;
+            rows += Paragraph::new(self.lines[start].clone())
+                .wrap(Wrap { trim: false })
+                .line_count(width);
+        }
+        let lines: Vec<_> = self.lines.range(start..).cloned().collect();
+        let paragraph = Paragraph::new(lines).wrap(Wrap { trim: false });
+        let top = paragraph.line_count(width).saturating_sub(wanted);
+        paragraph.scroll((top as u16, 0))
+    }
+}
+
+/// Whether `text` mentions `nick` as a word.
+fn mentions(text: &str, nick: &str) -> bool {
+    text.split(|c: char| !c.is_alphanumeric() && c != '_' && c != '-')
+        .any(|word| word.eq_ignore_ascii_case(nick))
+}
+
+fn same_room(a: &str, b: &str) -> bool {
+    a.trim_start_matches('#')
+        .eq_ignore_ascii_case(b.trim_start_matches('#'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An app with chat lines 0 to `n - 1` pending as messages 0 to
+    /// `n - 1`.
+    fn sending(n: u64) -> App {
+        let mut app = App::new();
+        for id in 0..n {
+            let line = app.show(Line::styled(format!("line {id}"), PENDING));
+            app.pending.push_back(Pending {
+                id,
+                line: Some(line),
+            });
+        }
+        app
+    }
+
+    fn style(app: &App, line: usize) -> Style {
+        app.lines[line].style
+    }
+
+    #[test]
+    fn unsolicited_errors_leave_pending_lines_alone() {
+        let mut app = sending(1);
+        app.settle(&Message::error("you were muted for 5m by op"));
+        assert_eq!(style(&app, 0), PENDING);
+
+        app.settle(&Message::Ack { id: 0 });
+        assert_eq!(style(&app, 0), SENT);
+        assert!(app.pending.is_empty());
+    }
+
+    #[test]
+    fn errors_fail_the_line_they_answer() {
+        let mut app = sending(2);
+        app.settle(&Message::Error {
+            id: Some(1),
+            text: "you are muted for 30s".to_string(),
+        });
+        assert_eq!((style(&app, 0), style(&app, 1)), (PENDING, FAILED));
+
+        // The muted message is still acknowledged afterwards.
+        app.settle(&Message::Ack { id: 0 });
+        app.settle(&Message::Ack { id: 1 });
+        assert_eq!((style(&app, 0), style(&app, 1)), (SENT, FAILED));
+    }
+
+    #[test]
+    fn unanswered_lines_fail_once_a_later_one_is_acknowledged() {
+        let mut app = sending(3);
+        app.settle(&Message::Ack { id: 1 });
+        assert_eq!(
+            (style(&app, 0), style(&app, 1), style(&app, 2)),
+            (FAILED, SENT, PENDING)
+        );
+    }
+
+    #[test]
+    fn lines_scrolled_off_are_not_restyled() {
+        let mut app = sending(1);
+        for _ in 0..SCROLLBACK {
+            app.notice("filler");
+        }
+        app.settle(&Message::Ack { id: 0 });
+        assert!(app.pending.is_empty());
+        assert!(app.lines.iter().all(|line| line.style != SENT));
+    }
+}
@@ -0,0 +1,258 @@
+//! Password accounts. When enabled, clients log in or register during the
+//! handshake and their account name becomes their nickname.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use password_hash::{rand_core::OsRng, SaltString};
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::task;
+
+use crate::{
+    error::Error,
+    users::{self, NickError},
+};
+
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// Account settings. Authentication is enabled by setting `accounts`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// File of `name:argon2-hash` lines, created if missing.
+    pub accounts: Option<PathBuf>,
+    /// Let clients create their own accounts with `/register`.
+    pub register: bool,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            accounts: None,
+            register: true,
+        }
+    }
+}
+
+/// Why a login or registration was refused.
+#[derive(Debug, Error)]
+pub enum AccountError {
+    #[error("account names follow the nickname rules: {0}")]
+    InvalidName(NickError),
+    #[error("passwords must be at least {MIN_PASSWORD_LEN} characters")]
+    WeakPassword,
+    #[error("account `{0}` already exists")]
+    Exists(String),
+    #[error("unknown account or wrong password")]
+    BadCredentials,
+    #[error("registration is closed")]
+    RegistrationClosed,
+    #[error("the account store failed")]
+    Store(#[source] Error),
+}
+
+/// The account file, loaded into memory. Names are unique ignoring ASCII
+/// case.
+#[derive(Debug)]
+pub(crate) struct Accounts {
+    path: PathBuf,
+    register: bool,
+    /// Account name and password hash, keyed by lowercase name.
+    accounts: Mutex<HashMap<String, (String, Arc<str>)>>,
+    /// Lowercase names being registered, held while the account is written
+    /// so that nobody else can take them.
+    reserved: Mutex<HashSet<String>>,
+    /// A hash no password matches. Logins to unknown accounts are checked
+    /// against it, so that they take as long to refuse as wrong passwords
+    /// and don't reveal which accounts exist.
+    dummy: Arc<str>,
+}
+
+impl Accounts {
+    /// Loads the account file, or returns `None` if accounts are disabled.
+    pub fn open(config: &AuthConfig) -> Result<Option<Self>, Error> {
+        let Some(path) = config.accounts.clone() else {
+            return Ok(None);
+        };
+        let failed = |source| Error::Accounts {
+            path: path.clone(),
+            source,
+        };
+        let accounts = load(&path).map_err(failed)?;
+        let dummy = hash(SaltString::generate(&mut OsRng).as_str()).map_err(failed)?;
+        Ok(Some(Accounts {
+            path,
+            register: config.register,
+            accounts: Mutex::new(accounts),
+            reserved: Mutex::new(HashSet::new()),
+            dummy: dummy.into(),
+        }))
+    }
+
+    /// Checks `password` and returns the account name as registered.
+    pub async fn login(&self, name: &str, password: &str) -> Result<String, AccountError> {
+        let account = self
+            .accounts
+            .lock()
+            .unwrap()
+            .get(&name.to_ascii_lowercase())
+            .cloned();
+        let (name, hash) = match account {
+            Some((name, hash)) => (Some(name), hash),
+            None => (None, self.dummy.clone()),
+        };
+
+        let password = password.to_string();
+        let verified = task::spawn_blocking(move || {
+            PasswordHash::new(&hash).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            })
+        })
+        .await
+        .expect("password verification panicked");
+        match name {
+            Some(name) if verified => Ok(name),
+            _ => Err(AccountError::BadCredentials),
+        }
+    }
+
+    /// Creates an account and returns its name.
+    pub async fn register(&self, name: &str, password: &str) -> Result<String, AccountError> {
+        if !self.register {
+            return Err(AccountError::RegistrationClosed);
+        }
+        users::validate(name).map_err(AccountError::InvalidName)?;
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            return Err(AccountError::WeakPassword);
+        }
+        let key = name.to_ascii_lowercase();
+        let reservation = {
+            let accounts = self.accounts.lock().unwrap();
+            let mut reserved = self.reserved.lock().unwrap();
+            if accounts.contains_key(&key) || !reserved.insert(key.clone()) {
+                return Err(AccountError::Exists(name.to_string()));
+            }
+            Reservation {
+                reserved: &self.reserved,
+                key,
+            }
+        };
+
+        // Hashing and writing the file happen off the runtime and without
+        // the lock, so that logins carry on meanwhile.
+        let (path, account, password) = (self.path.clone(), name.to_string(), password.to_string());
+        let hash = task::spawn_blocking(move || {
+            let hash = hash(&password)?;
+            append(&path, &account, &hash)?;
+            Ok(hash)
+        })
+        .await
+        .expect("account creation panicked")
+        .map_err(|source| {
+            AccountError::Store(Error::Accounts {
+                path: self.path.clone(),
+                source,
+            })
+        })?;
+
+        let mut accounts = self.accounts.lock().unwrap();
+        accounts.insert(reservation.key.clone(), (name.to_string(), hash.into()));
+        Ok(name.to_string())
+    }
+}
+
+/// A name being registered. Dropping it frees the name, whether the account
+/// was created or not.
+struct Reservation<'a> {
+    reserved: &'a Mutex<HashSet<String>>,
+    key: String,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.reserved.lock().unwrap().remove(&self.key);
+    }
+}
+
+fn append(path: &Path, name: &str, hash: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    // One write, so that concurrent registrations don't interleave.
+    file.write_all(format!("{name}:{hash}\n").as_bytes())?;
+    file.sync_data()
+}
+
+/// Hashes `password` with a fresh salt.
+fn hash(password: &str) -> io::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| io::Error::other(err.to_string()))
+}
+
+fn load(path: &Path) -> io::Result<HashMap<String, (String, Arc<str>)>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+    let mut accounts = HashMap::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((name, hash)) = line.split_once(':') else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: expected `name:hash`", number + 1),
+            ));
+        };
+        accounts.insert(name.to_ascii_lowercase(), (name.to_string(), hash.into()));
+    }
+    Ok(accounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn registered_accounts_are_saved_and_names_taken_once() {
+        let path = std::env::temp_dir().join(format!("msg_server-accounts-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let config = AuthConfig {
+            accounts: Some(path.clone()),
+            register: true,
+        };
+        let accounts = Accounts::open(&config).unwrap().unwrap();
+
+        let (first, second) = tokio::join!(
+            accounts.register("Alice", "correct horse"),
+            accounts.register("alice", "battery staple"),
+        );
+        assert_eq!(first.unwrap(), "Alice");
+        assert!(matches!(second, Err(AccountError::Exists(_))));
+        assert!(accounts.reserved.lock().unwrap().is_empty());
+
+        let reopened = Accounts::open(&config).unwrap().unwrap();
+        assert_eq!(
+            reopened.login("ALICE", "correct horse").await.unwrap(),
+            "Alice"
+        );
+        assert!(matches!(
+            reopened.login("alice", "battery staple").await,
+            Err(AccountError::BadCredentials)
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+//! The admin socket: a Unix domain socket that takes one JSON
+//! [`AdminRequest`] per line and answers each with one JSON
+//! [`AdminResponse`] line. The `msg_server-admin` tool speaks it.
+
+use std::{net::SocketAddr, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::server::Endpoint;
+
+/// Where the admin socket listens.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Path of the Unix socket, created readable and writable only by the
+    /// server's user. `None` disables the admin socket.
+    pub socket: Option<PathBuf>,
+}
+
+/// Something asked of the server over the admin socket.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum AdminRequest {
+    /// List the open connections.
+    Clients,
+    /// List the open rooms and their members.
+    Rooms,
+    /// Disconnect the client called `nick`.
+    Kick {
+        nick: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// Send a system notice to every client.
+    Notice {
+        text: String,
+    },
+    /// Re-read the config and apply the settings that can change at
+    /// runtime.
+    Reload,
+    Shutdown,
+}
+
+/// The server's answer to an [`AdminRequest`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AdminResponse {
+    Clients {
+        clients: Vec<ClientInfo>,
+    },
+    Rooms {
+        rooms: Vec<RoomInfo>,
+    },
+    /// The request was carried out.
+    Done {
+        text: String,
+    },
+    Error {
+        text: String,
+    },
+}
+
+/// An open connection, as listed by [`AdminRequest::Clients`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub addr: SocketAddr,
+    pub endpoint: Endpoint,
+    /// `None` until the client has chosen a nickname or logged in.
+    pub nick: Option<String>,
+    pub rooms: Vec<String>,
+    /// Seconds since the client connected.
+    pub connected_secs: u64,
+    /// Messages and commands the client has sent.
+    pub messages: u64,
+    /// Average messages per minute since the client connected.
+    pub rate: f64,
+    /// Times the client fell behind and missed messages.
+    pub lags: u64,
+}
+
+/// An open room, as listed by [`AdminRequest::Rooms`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: String,
+    /// Nicknames, sorted.
+    pub members: Vec<String>,
+}
+
+#[cfg(unix)]
+pub(crate) use listener::{bind, serve};
+
+#[cfg(unix)]
+mod listener {
+    use std::{
+        fs, io,
+        os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{UnixListener, UnixStream},
+    };
+
+    use super::{AdminRequest, AdminResponse};
+    use crate::{
+        error::Error,
+        server::{self, AcceptBackoff, ServerHandle, Shared},
+    };
+
+    /// The longest request line a session reads before giving up on it.
+    const MAX_REQUEST_LEN: usize = 64 * 1024;
+
+    /// Binds the admin socket at `path`, replacing a stale socket left by a
+    /// server that is no longer running.
+    ///
+    /// The socket is bound inside a directory only the server's user can
+    /// enter and moved into place once it is `0o600`, so it is never
+    /// reachable by anyone else.
+    pub fn bind(path: &Path) -> io::Result<UnixListener> {
+        if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "another server is listening",
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+        let name = path.file_name().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "socket path has no file name")
+        })?;
+        let mut private = path.as_os_str().to_owned();
+        private.push(format!(".{}", std::process::id()));
+        let private = PathBuf::from(private);
+        fs::DirBuilder::new().mode(0o700).create(&private)?;
+        let staged = private.join(name);
+        let bound = UnixListener::bind(&staged).and_then(|listener| {
+            fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+            fs::rename(&staged, path)?;
+            Ok(listener)
+        });
+        let _ = fs::remove_file(&staged);
+        let _ = fs::remove_dir(&private);
+        bound
+    }
+
+    /// Serves admin clients until the server shuts down, then removes the
+    /// socket.
+    pub async fn serve(listener: UnixListener, path: PathBuf, shared: Arc<Shared>) {
+        let mut shutdown = shared.shutdown.subscribe();
+        let mut backoff = AcceptBackoff::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        backoff.reset();
+                        let (path, shared) = (path.clone(), shared.clone());
+                        let handle = ServerHandle::new(shared.clone());
+                        tokio::spawn(async move {
+                            if let Err(source) = session(stream, handle).await {
+                                shared.hooks.error(&Error::Admin { path, source });
+                            }
+                        });
+                    }
+                    Err(source) => {
+                        shared.hooks.error(&Error::Admin {
+                            path: path.clone(),
+                            source,
+                        });
+                        if !backoff.wait(&mut shutdown).await {
+                            break;
+                        }
+                    }
+                },
+                _ = server::stopped(&mut shutdown) => break,
+            }
+        }
+        let _ = fs::remove_file(&path);
+    }
+
+    /// Answers requests until the client hangs up or sends a line longer
+    /// than [`MAX_REQUEST_LEN`], which ends the session after an error.
+    async fn session(stream: UnixStream, handle: ServerHandle) -> io::Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut reader = BufReader::new(read);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let limit = MAX_REQUEST_LEN as u64 + 1;
+            if (&mut reader)
+                .take(limit)
+                .read_until(b'\n', &mut line)
+                .await?
+                == 0
+            {
+                return Ok(());
+            }
+            let too_long = line.len() > MAX_REQUEST_LEN && !line.ends_with(b"\n");
+            let response = if too_long {
+                AdminResponse::Error {
+                    text: format!("request longer than {MAX_REQUEST_LEN} bytes"),
+                }
+            } else if line.trim_ascii().is_empty() {
+                continue;
+            } else {
+                match serde_json::from_slice(&line) {
+                    Ok(request) => respond(request, &handle),
+                    Err(err) => AdminResponse::Error {
+                        text: format!("invalid request: {err}"),
+                    },
+                }
+            };
+            let mut json = serde_json::to_vec(&response)?;
+            json.push(b'\n');
+            write.write_all(&json).await?;
+            if too_long {
+                return Ok(());
+            }
+        }
+    }
+
+    fn respond(request: AdminRequest, handle: &ServerHandle) -> AdminResponse {
+        let done = |text: &str| AdminResponse::Done {
+            text: text.to_string(),
+        };
+        match request {
+            AdminRequest::Clients => AdminResponse::Clients {
+                clients: handle.clients(),
+            },
+            AdminRequest::Rooms => AdminResponse::Rooms {
+                rooms: handle.room_members(),
+            },
+            AdminRequest::Kick { nick, reason } => match handle.kick(&nick, reason) {
+                Some(nick) => done(&format!("kicked {nick}")),
+                None => AdminResponse::Error {
+                    text: format!("{nick} is not online"),
+                },
+            },
+            AdminRequest::Notice { text } => {
+                handle.notice(text);
+                done("notice sent")
+            }
+            AdminRequest::Reload => match handle.reload() {
+                Ok(()) => done("config reloaded"),
+                Err(err) => AdminResponse::Error {
+                    text: err.to_string(),
+                },
+            },
+            AdminRequest::Shutdown => {
+                handle.shutdown();
+                done("shutting down")
+            }
+        }
+    }
+}
@@ -0,0 +1,304 @@
+//! Decides which incoming connections are served.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::config::ConfigError;
+
+/// Connections being turned away politely at once. Past this, refused
+/// sockets are closed without a message.
+const MAX_REFUSING: usize = 64;
+
+/// Limits on who may connect and how many at once.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdmissionConfig {
+    /// Connections served at once. `None` for no limit.
+    pub max_connections: Option<usize>,
+    /// Connections served at once from a single IP address.
+    pub max_per_ip: Option<usize>,
+    /// If not empty, only addresses in these networks may connect.
+    pub allow: Vec<Cidr>,
+    /// Addresses in these networks may not connect, even if allowed.
+    pub deny: Vec<Cidr>,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        AdmissionConfig {
+            max_connections: Some(1024),
+            max_per_ip: Some(32),
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+}
+
+impl AdmissionConfig {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_connections == Some(0) || self.max_per_ip == Some(0) {
+            return Err(ConfigError::ZeroAdmissionLimit);
+        }
+        Ok(())
+    }
+}
+
+/// An IP network such as `10.0.0.0/8` or `2001:db8::/32`. A bare address is
+/// a network of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid network `{0}`: expected `ip` or `ip/prefix`")]
+pub struct CidrError(String);
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, CidrError> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            return Err(CidrError(format!("{addr}/{prefix}")));
+        }
+        Ok(Cidr { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || CidrError(s.to_string());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = canonical(addr.parse().map_err(|_| invalid())?);
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Cidr::new(addr, prefix).map_err(|_| invalid())
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = CidrError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Treats IPv4 clients of a dual-stack listener as IPv4.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(IpAddr::V6(v6), IpAddr::V4),
+        ip => ip,
+    }
+}
+
+/// Why a connection was turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum Refusal {
+    #[error("connections from your address are not allowed")]
+    Denied,
+    #[error("server full, try again later")]
+    Full,
+    #[error("too many connections from your address")]
+    TooManyFromIp,
+    #[error("you are banned from this server")]
+    Banned,
+}
+
+/// Counts the connections being served.
+#[derive(Debug)]
+pub(crate) struct Admission {
+    config: RwLock<AdmissionConfig>,
+    counts: Arc<Mutex<Counts>>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+    refusing: usize,
+}
+
+/// Held for as long as an admitted connection is served.
+#[derive(Debug)]
+pub(crate) struct Ticket {
+    ip: IpAddr,
+    counts: Arc<Mutex<Counts>>,
+}
+
+/// Held while a refused connection is told why.
+#[derive(Debug)]
+pub(crate) struct RefusalTicket {
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl Admission {
+    pub fn new(config: AdmissionConfig) -> Self {
+        Admission {
+            config: RwLock::new(config),
+            counts: Arc::default(),
+        }
+    }
+
+    /// Applies new limits. Connections already admitted stay open.
+    pub fn reconfigure(&self, config: AdmissionConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    /// Admits a connection from `ip`, or says why not.
+    pub fn admit(&self, ip: IpAddr) -> Result<Ticket, Refusal> {
+        let ip = canonical(ip);
+        let config = self.config.read().unwrap();
+        let allowed = config.allow.is_empty() || config.allow.iter().any(|net| net.contains(ip));
+        if !allowed || config.deny.iter().any(|net| net.contains(ip)) {
+            return Err(Refusal::Denied);
+        }
+
+        let mut counts = self.counts.lock().unwrap();
+        if config
+            .max_connections
+            .is_some_and(|max| counts.total >= max)
+        {
+            return Err(Refusal::Full);
+        }
+        let from_ip = counts.by_ip.get(&ip).copied().unwrap_or(0);
+        if config.max_per_ip.is_some_and(|max| from_ip >= max) {
+            return Err(Refusal::TooManyFromIp);
+        }
+        counts.total += 1;
+        counts.by_ip.insert(ip, from_ip + 1);
+        Ok(Ticket {
+            ip,
+            counts: self.counts.clone(),
+        })
+    }
+
+    /// Reserves a slot for telling a refused client why, unless too many
+    /// are being told already.
+    pub fn refusing(&self) -> Option<RefusalTicket> {
+        let mut counts = self.counts.lock().unwrap();
+        if counts.refusing >= MAX_REFUSING {
+            return None;
+        }
+        counts.refusing += 1;
+        Some(RefusalTicket {
+            counts: self.counts.clone(),
+        })
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(count) = counts.by_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.by_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl Drop for RefusalTicket {
+    fn drop(&mut self) {
+        self.counts.lock().unwrap().refusing -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn networks_contain_their_addresses() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.0.0")));
+        assert!(net.contains(ip("10.1.255.255")));
+        assert!(!net.contains(ip("10.2.0.0")));
+
+        let net: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(ip("2001:db8:ffff::1")));
+        assert!(!net.contains(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn bare_addresses_and_zero_prefixes() {
+        let one: Cidr = "192.0.2.7".parse().unwrap();
+        assert!(one.contains(ip("192.0.2.7")));
+        assert!(!one.contains(ip("192.0.2.8")));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(ip("203.0.113.1")));
+        assert!(
+            !all.contains(ip("::1")),
+            "an IPv4 network has no IPv6 addresses"
+        );
+    }
+
+    #[test]
+    fn mapped_addresses_count_as_ipv4() {
+        let net: Cidr = "127.0.0.0/8".parse().unwrap();
+        assert!(net.contains(ip("::ffff:127.0.0.1")));
+        let mapped: Cidr = "::ffff:10.0.0.1".parse().unwrap();
+        assert_eq!(mapped.to_string(), "10.0.0.1/32");
+    }
+
+    #[test]
+    fn invalid_networks_are_refused() {
+        for s in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "example.com",
+            "10.0.0/8",
+        ] {
+            assert!(s.parse::<Cidr>().is_err(), "{s}");
+        }
+    }
+}
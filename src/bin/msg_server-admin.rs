@@ -0,0 +1,155 @@
+//! Talks to a running server over its admin socket.
+
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::{Parser, Subcommand};
+use msg_server::admin::{AdminRequest, AdminResponse, ClientInfo, RoomInfo};
+
+/// Inspect and control a running msg_server.
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Args {
+    /// The server's admin socket (`admin.socket` in its config).
+    #[arg(short, long, value_name = "PATH")]
+    socket: PathBuf,
+
+    /// Print the server's JSON response instead of a summary.
+    #[arg(long)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// List the open connections.
+    Clients,
+    /// List the open rooms and their members.
+    Rooms,
+    /// Disconnect a client.
+    Kick {
+        nick: String,
+        /// Shown to everyone with the kick.
+        reason: Option<String>,
+    },
+    /// Send a system notice to every client.
+    Notice {
+        #[arg(required = true, num_args = 1..)]
+        text: Vec<String>,
+    },
+    /// Re-read the config file and apply what can change without a restart.
+    Reload,
+    /// Disconnect everyone and stop the server.
+    Shutdown,
+}
+
+impl From<Command> for AdminRequest {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::Clients => AdminRequest::Clients,
+            Command::Rooms => AdminRequest::Rooms,
+            Command::Kick { nick, reason } => AdminRequest::Kick { nick, reason },
+            Command::Notice { text } => AdminRequest::Notice {
+                text: text.join(" "),
+            },
+            Command::Reload => AdminRequest::Reload,
+            Command::Shutdown => AdminRequest::Shutdown,
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let response = match request(&args.socket, &args.command.into()) {
+        Ok(response) => response,
+        Err(err) => {
+            eprintln!("error: {}: {err}", args.socket.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    if args.json {
+        println!("{response}");
+        return ExitCode::SUCCESS;
+    }
+
+    match serde_json::from_str(&response) {
+        Ok(AdminResponse::Clients { clients }) => print_clients(&clients),
+        Ok(AdminResponse::Rooms { rooms }) => print_rooms(&rooms),
+        Ok(AdminResponse::Done { text }) => println!("{text}"),
+        Ok(AdminResponse::Error { text }) => {
+            eprintln!("error: {text}");
+            return ExitCode::FAILURE;
+        }
+        Err(err) => {
+            eprintln!("error: unexpected response: {err}");
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Sends `request` and returns the response line.
+#[cfg(unix)]
+fn request(socket: &std::path::Path, request: &AdminRequest) -> std::io::Result<String> {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        os::unix::net::UnixStream,
+    };
+
+    let mut stream = UnixStream::connect(socket)?;
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    Ok(response.trim_end().to_string())
+}
+
+#[cfg(not(unix))]
+fn request(_: &std::path::Path, _: &AdminRequest) -> std::io::Result<String> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+fn print_clients(clients: &[ClientInfo]) {
+    if clients.is_empty() {
+        println!("no clients connected");
+        return;
+    }
+    println!(
+        "{:<24} {:<16} {:<16} {:>8} {:>8} {:>8} {:>5}  ROOMS",
+        "ADDRESS", "ENDPOINT", "NICK", "UP", "MSGS", "MSG/MIN", "LAGS"
+    );
+    for client in clients {
+        let endpoint = serde_json::to_value(client.endpoint)
+            .ok()
+            .and_then(|value| value.as_str().map(str::to_string))
+            .unwrap_or_default();
+        println!(
+            "{:<24} {:<16} {:<16} {:>7}s {:>8} {:>8.1} {:>5}  {}",
+            client.addr,
+            endpoint,
+            client.nick.as_deref().unwrap_or("-"),
+            client.connected_secs,
+            client.messages,
+            client.rate,
+            client.lags,
+            client.rooms.join(" "),
+        );
+    }
+}
+
+fn print_rooms(rooms: &[RoomInfo]) {
+    if rooms.is_empty() {
+        println!("no rooms are open");
+    }
+    for room in rooms {
+        println!(
+            "{} ({}): {}",
+            room.name,
+            room.members.len(),
+            room.members.join(", ")
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+};
+
+use tokio::sync::broadcast;
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    Stream,
+};
+
+use crate::{
+    history::Log,
+    protocol::{self, Message, Since},
+};
+
+/// A message broadcast to every subscriber, tagged with the address of the
+/// connection that caused it.
+#[derive(Debug, Clone)]
+pub(crate) struct Broadcast {
+    pub seq: u64,
+    pub from: SocketAddr,
+    pub msg: Message,
+}
+
+/// A broadcast channel that numbers its messages and keeps the most recent
+/// ones so lagging subscribers can catch up. Chat messages are also recorded
+/// in the channel's [`Log`], if it has one.
+#[derive(Debug)]
+pub(crate) struct Channel {
+    tx: broadcast::Sender<Broadcast>,
+    history: Mutex<History>,
+    log: Option<Arc<Mutex<Log>>>,
+}
+
+#[derive(Debug)]
+struct History {
+    next_seq: u64,
+    capacity: usize,
+    entries: VecDeque<Broadcast>,
+}
+
+/// A stream of [`Delivery`]s from a [`Channel`].
+pub(crate) struct Subscription {
+    channel: Arc<Channel>,
+    rx: BroadcastStream<Broadcast>,
+    last_seq: u64,
+}
+
+pub(crate) enum Delivery {
+    Message(Broadcast),
+    /// The subscriber fell behind. `missed` messages are gone for good;
+    /// `replay` holds the ones recovered from history, oldest first.
+    Lagged {
+        missed: u64,
+        replay: Vec<Broadcast>,
+    },
+}
+
+impl Channel {
+    pub fn new(capacity: usize, history: usize) -> Arc<Self> {
+        Self::with_log(capacity, history, None)
+    }
+
+    /// A channel whose chat messages are recorded in `log`. Sequence numbers
+    /// continue from the last logged message.
+    pub fn with_log(capacity: usize, history: usize, log: Option<Arc<Mutex<Log>>>) -> Arc<Self> {
+        let (tx, _rx) = broadcast::channel(capacity);
+        let last_id = log.as_ref().map_or(0, |log| log.lock().unwrap().last_id());
+        Arc::new(Channel {
+            tx,
+            history: Mutex::new(History {
+                next_seq: last_id + 1,
+                capacity: history,
+                entries: VecDeque::with_capacity(history),
+            }),
+            log,
+        })
+    }
+
+    pub fn subscribe(self: &Arc<Self>) -> Subscription {
+        self.subscribe_replaying(None, 0).0
+    }
+
+    /// Subscribes and returns the logged messages the subscriber should see
+    /// first: those after `since` if given, otherwise the last `recent`.
+    pub fn subscribe_replaying(
+        self: &Arc<Self>,
+        since: Option<Since>,
+        recent: usize,
+    ) -> (Subscription, Vec<Message>) {
+        // Subscribe under the history lock so `last_seq` matches the point
+        // where the receiver starts and the replay ends.
+        let history = self.history.lock().unwrap();
+        let replay = match (&self.log, since) {
+            (None, _) => Vec::new(),
+            (Some(log), Some(since)) => log.lock().unwrap().since(since),
+            (Some(log), None) => log.lock().unwrap().recent(recent),
+        };
+        let subscription = Subscription {
+            channel: self.clone(),
+            rx: BroadcastStream::new(self.tx.subscribe()),
+            last_seq: history.next_seq - 1,
+        };
+        (subscription, replay)
+    }
+
+    /// Broadcasts `msg`. Chat messages are stamped with their sequence
+    /// number and the current time, and logged.
+    pub fn send(&self, from: SocketAddr, mut msg: Message) {
+        let mut history = self.history.lock().unwrap();
+        let seq = history.next_seq;
+        if let Message::Chat { id, ts, .. } = &mut msg {
+            *id = Some(seq);
+            *ts = Some(protocol::timestamp());
+        }
+        let msg = Broadcast { seq, from, msg };
+        history.next_seq += 1;
+        if history.capacity > 0 {
+            if history.entries.len() == history.capacity {
+                history.entries.pop_front();
+            }
+            history.entries.push_back(msg.clone());
+        }
+        if let (Some(log), Message::Chat { .. }) = (&self.log, &msg.msg) {
+            log.lock().unwrap().append(&msg.msg);
+        }
+        // Sending only fails when nobody is subscribed, which is fine.
+        let _ = self.tx.send(msg);
+    }
+
+    /// Works out what a subscriber that last saw `last_seq` and was told it
+    /// skipped `skipped` messages can still recover.
+    fn resync(&self, last_seq: u64, skipped: u64) -> (u64, Vec<Broadcast>) {
+        let history = self.history.lock().unwrap();
+        if history.capacity == 0 {
+            return (skipped, Vec::new());
+        }
+
+        let replay: Vec<_> = history
+            .entries
+            .iter()
+            .filter(|msg| msg.seq > last_seq)
+            .cloned()
+            .collect();
+        let missed = replay.first().map_or(0, |first| first.seq - last_seq - 1);
+        (missed, replay)
+    }
+}
+
+impl Subscription {
+    pub fn channel(&self) -> &Arc<Channel> {
+        &self.channel
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Delivery;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Delivery>> {
+        loop {
+            match ready!(Pin::new(&mut self.rx).poll_next(cx)) {
+                Some(Ok(msg)) => {
+                    // Already delivered by an earlier resync.
+                    if msg.seq <= self.last_seq {
+                        continue;
+                    }
+                    self.last_seq = msg.seq;
+                    return Poll::Ready(Some(Delivery::Message(msg)));
+                }
+                Some(Err(BroadcastStreamRecvError::Lagged(skipped))) => {
+                    let (missed, replay) = self.channel.resync(self.last_seq, skipped);
+                    if let Some(last) = replay.last() {
+                        self.last_seq = last.seq;
+                    }
+                    return Poll::Ready(Some(Delivery::Lagged { missed, replay }));
+                }
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
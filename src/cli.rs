@@ -0,0 +1,147 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use msg_server::config::{Config, ConfigError};
+
+/// A line-based broadcast chat server.
+#[derive(Debug, Clone, Parser)]
+#[command(version)]
+pub struct Args {
+    /// Read settings from this TOML file.
+    #[arg(short, long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on (`ip`, `ip:port` or `[ipv6]:port`). May be
+    /// given more than once; replaces the `listen` list from the config file.
+    #[arg(short, long = "listen", value_name = "ADDR")]
+    pub listen: Vec<String>,
+
+    /// Port for listen addresses that don't specify one.
+    #[arg(short, long)]
+    pub port: Option<u16>,
+
+    /// Address to accept WebSocket connections on. May be given more than
+    /// once; replaces `websocket.listen` from the config file.
+    #[arg(long = "ws-listen", value_name = "ADDR")]
+    pub ws_listen: Vec<String>,
+
+    /// Address to accept TLS connections on. May be given more than once;
+    /// replaces `tls.listen` from the config file.
+    #[arg(long = "tls-listen", value_name = "ADDR")]
+    pub tls_listen: Vec<String>,
+
+    /// Address to serve Prometheus metrics on at `/metrics`. May be given
+    /// more than once; replaces `metrics.listen` from the config file.
+    #[arg(long = "metrics-listen", value_name = "ADDR")]
+    pub metrics_listen: Vec<String>,
+
+    /// PEM certificate chain for TLS listeners.
+    #[arg(long, value_name = "PATH")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for `--tls-cert`.
+    #[arg(long, value_name = "PATH")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Require TLS clients to present a certificate signed by a CA in this
+    /// PEM file.
+    #[arg(long, value_name = "PATH")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Keep room history in this directory so it survives restarts.
+    #[arg(long, value_name = "DIR")]
+    pub history_dir: Option<PathBuf>,
+
+    /// Require clients to log in to an account from this file.
+    #[arg(long, value_name = "PATH")]
+    pub accounts: Option<PathBuf>,
+
+    /// Keep the ban list in this file so bans survive restarts.
+    #[arg(long, value_name = "PATH")]
+    pub bans: Option<PathBuf>,
+
+    /// Serve the admin API on a Unix socket at this path.
+    #[arg(long, value_name = "PATH")]
+    pub admin_socket: Option<PathBuf>,
+
+    /// What to log: a level such as `debug`, or directives such as
+    /// `warn,msg_server=debug`.
+    #[arg(long, value_name = "FILTER")]
+    pub log_level: Option<String>,
+
+    /// Log one JSON object per line.
+    #[arg(long)]
+    pub log_json: bool,
+
+    /// Connections served at once.
+    #[arg(long, value_name = "N")]
+    pub max_connections: Option<usize>,
+
+    /// Messages buffered per client before it starts lagging.
+    #[arg(long, value_name = "N")]
+    pub channel_capacity: Option<usize>,
+}
+
+impl Args {
+    /// Builds the effective config: defaults, then the config file, then
+    /// command-line flags.
+    pub fn load(self) -> Result<Config, ConfigError> {
+        let mut config = match &self.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+
+        if !self.listen.is_empty() {
+            config.listen = self.listen;
+        }
+        if !self.ws_listen.is_empty() {
+            config.websocket.listen = self.ws_listen;
+        }
+        if !self.tls_listen.is_empty() {
+            config.tls.listen = self.tls_listen;
+        }
+        if !self.metrics_listen.is_empty() {
+            config.metrics.listen = self.metrics_listen;
+        }
+        if self.tls_cert.is_some() {
+            config.tls.cert = self.tls_cert;
+        }
+        if self.tls_key.is_some() {
+            config.tls.key = self.tls_key;
+        }
+        if self.tls_client_ca.is_some() {
+            config.tls.client_ca = self.tls_client_ca;
+        }
+        if self.history_dir.is_some() {
+            config.history.dir = self.history_dir;
+        }
+        if self.accounts.is_some() {
+            config.auth.accounts = self.accounts;
+        }
+        if self.bans.is_some() {
+            config.moderation.bans = self.bans;
+        }
+        if self.admin_socket.is_some() {
+            config.admin.socket = self.admin_socket;
+        }
+        if let Some(level) = self.log_level {
+            config.log.level = level;
+        }
+        if self.log_json {
+            config.log.json = true;
+        }
+        if self.max_connections.is_some() {
+            config.admission.max_connections = self.max_connections;
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(capacity) = self.channel_capacity {
+            config.channel_capacity = capacity;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+}
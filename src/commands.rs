@@ -0,0 +1,468 @@
+//! Slash commands: lines starting with `/` that the server answers itself
+//! instead of relaying. Replies go only to the client that sent them.
+
+use std::{collections::BTreeMap, fmt, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
+
+use thiserror::Error;
+
+use crate::{
+    moderation::{self, BanTarget, BanTargetError},
+    protocol::{ParseSinceError, Since},
+    server::ServerHandle,
+};
+
+/// A built-in command's name, arguments and description, as `/help` shows
+/// them.
+struct Builtin {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+}
+
+const BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "help",
+        usage: "/help [command]",
+        help: "list the commands, or describe one",
+    },
+    Builtin {
+        name: "nick",
+        usage: "/nick <name>",
+        help: "change your nickname",
+    },
+    Builtin {
+        name: "join",
+        usage: "/join <room> [since]",
+        help: "join a room, or talk in one you are in; `since` is a message id or @timestamp to replay from",
+    },
+    Builtin {
+        name: "part",
+        usage: "/part [room]",
+        help: "leave a room, by default the one you are talking in",
+    },
+    Builtin {
+        name: "msg",
+        usage: "/msg <nick> <text>",
+        help: "send a private message",
+    },
+    Builtin {
+        name: "rooms",
+        usage: "/rooms",
+        help: "list the open rooms",
+    },
+    Builtin {
+        name: "names",
+        usage: "/names [room]",
+        help: "list the members of a room",
+    },
+    Builtin {
+        name: "who",
+        usage: "/who [room]",
+        help: "list everyone online, or the members of a room; also /list",
+    },
+    Builtin {
+        name: "quit",
+        usage: "/quit [reason]",
+        help: "disconnect",
+    },
+    Builtin {
+        name: "oper",
+        usage: "/oper <password>",
+        help: "become an operator",
+    },
+    Builtin {
+        name: "kick",
+        usage: "/kick <nick> [reason]",
+        help: "disconnect someone (operators)",
+    },
+    Builtin {
+        name: "ban",
+        usage: "/ban <nick|ip|network> [duration] [reason]",
+        help: "disconnect and keep out a nickname or addresses, for a duration like 30m or for good (operators)",
+    },
+    Builtin {
+        name: "unban",
+        usage: "/unban <nick|ip|network>",
+        help: "lift a ban (operators)",
+    },
+    Builtin {
+        name: "bans",
+        usage: "/bans",
+        help: "list the bans in force (operators)",
+    },
+    Builtin {
+        name: "mute",
+        usage: "/mute <nick> [duration] [reason]",
+        help: "stop someone talking, by default for 5m (operators)",
+    },
+];
+
+fn builtin(name: &str) -> Option<&'static Builtin> {
+    let name = if name == "list" { "who" } else { name };
+    BUILTINS.iter().find(|builtin| builtin.name == name)
+}
+
+/// A parsed slash command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Help(Option<String>),
+    Nick(String),
+    Join {
+        room: String,
+        since: Option<Since>,
+    },
+    /// Leave a room, or the active room if `None`.
+    Part(Option<String>),
+    Msg {
+        to: String,
+        text: String,
+    },
+    Rooms,
+    Names(Option<String>),
+    /// List the members of a room, or everyone online if `None`.
+    Who(Option<String>),
+    Quit(Option<String>),
+    Oper(String),
+    Kick {
+        nick: String,
+        reason: Option<String>,
+    },
+    /// Ban a target, for good if `duration` is `None`.
+    Ban {
+        target: BanTarget,
+        duration: Option<Duration>,
+        reason: Option<String>,
+    },
+    Unban(BanTarget),
+    Bans,
+    /// Mute a client, for [`DEFAULT_MUTE`](moderation::DEFAULT_MUTE) if
+    /// `duration` is `None`.
+    Mute {
+        nick: String,
+        duration: Option<Duration>,
+        reason: Option<String>,
+    },
+    /// Any other command, for the handlers registered with
+    /// [`ServerBuilder::command`](crate::ServerBuilder::command).
+    Custom {
+        name: String,
+        args: String,
+    },
+}
+
+/// Why a command line could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum CommandError {
+    #[error("empty command; try /help")]
+    Empty,
+    #[error("usage: {0}")]
+    Usage(&'static str),
+    #[error("/join <room> <since>: {0}")]
+    Since(ParseSinceError),
+    #[error(transparent)]
+    Target(BanTargetError),
+}
+
+impl FromStr for Command {
+    type Err = CommandError;
+
+    /// Parses a line such as `/join #rust`. The leading `/` is optional.
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let line = line.strip_prefix('/').unwrap_or(line).trim();
+        let (name, args) = line
+            .split_once(char::is_whitespace)
+            .map_or((line, ""), |(name, args)| (name, args.trim()));
+        let optional = || (!args.is_empty()).then(|| args.to_string());
+        let usage = || CommandError::Usage(builtin(name).map_or("", |builtin| builtin.usage));
+
+        Ok(match name {
+            "" => return Err(CommandError::Empty),
+            "help" => Command::Help(optional().map(|topic| topic.trim_start_matches('/').into())),
+            "nick" if args.is_empty() => return Err(usage()),
+            "nick" => Command::Nick(args.to_string()),
+            "join" => {
+                let (room, since) = args
+                    .split_once(char::is_whitespace)
+                    .map_or((args, None), |(room, since)| (room, Some(since.trim())));
+                if room.is_empty() {
+                    return Err(usage());
+                }
+                Command::Join {
+                    room: room.to_string(),
+                    since: since
+                        .map(str::parse)
+                        .transpose()
+                        .map_err(CommandError::Since)?,
+                }
+            }
+            "part" => Command::Part(optional()),
+            "msg" => match args.split_once(char::is_whitespace) {
+                Some((to, text)) => Command::Msg {
+                    to: to.to_string(),
+                    text: text.trim().to_string(),
+                },
+                None => return Err(usage()),
+            },
+            "rooms" => Command::Rooms,
+            "names" => Command::Names(optional()),
+            "who" | "list" => Command::Who(optional()),
+            "quit" => Command::Quit(optional()),
+            "oper" if args.is_empty() => return Err(usage()),
+            "oper" => Command::Oper(args.to_string()),
+            "kick" => {
+                let (nick, reason) = split(args);
+                if nick.is_empty() {
+                    return Err(usage());
+                }
+                Command::Kick {
+                    nick: nick.to_string(),
+                    reason,
+                }
+            }
+            "ban" => {
+                let (target, rest) = split(args);
+                if target.is_empty() {
+                    return Err(usage());
+                }
+                let (duration, reason) = duration(rest);
+                Command::Ban {
+                    target: target.parse().map_err(CommandError::Target)?,
+                    duration,
+                    reason,
+                }
+            }
+            "unban" if args.is_empty() => return Err(usage()),
+            "unban" => Command::Unban(args.parse().map_err(CommandError::Target)?),
+            "bans" => Command::Bans,
+            "mute" => {
+                let (nick, rest) = split(args);
+                if nick.is_empty() {
+                    return Err(usage());
+                }
+                let (duration, reason) = duration(rest);
+                Command::Mute {
+                    nick: nick.to_string(),
+                    duration,
+                    reason,
+                }
+            }
+            _ => Command::Custom {
+                name: name.to_string(),
+                args: args.to_string(),
+            },
+        })
+    }
+}
+
+/// Splits off the first word of `args`, returning the rest if there is any.
+fn split(args: &str) -> (&str, Option<String>) {
+    match args.split_once(char::is_whitespace) {
+        Some((first, rest)) => (first, Some(rest.trim().to_string())),
+        None => (args, None),
+    }
+}
+
+/// Takes a leading duration such as `10m` off `rest`.
+fn duration(rest: Option<String>) -> (Option<Duration>, Option<String>) {
+    let Some(rest) = rest else {
+        return (None, None);
+    };
+    let (first, after) = split(&rest);
+    match moderation::parse_duration(first) {
+        Some(duration) => (Some(duration), after),
+        None => (None, Some(rest)),
+    }
+}
+
+/// A custom command being run, as passed to its handler.
+#[derive(Debug)]
+pub struct Invocation<'a> {
+    /// The issuing client's nickname and address.
+    pub nick: &'a str,
+    pub addr: SocketAddr,
+    /// The room the client is talking in, if any.
+    pub room: Option<&'a str>,
+    /// Everything after the command name, trimmed.
+    pub args: &'a str,
+    pub server: &'a ServerHandle,
+}
+
+/// Runs a custom command. `Ok` text is sent to the issuer as a notice and
+/// `Err` text as an error; empty text sends nothing.
+type Handler = Arc<dyn Fn(&Invocation<'_>) -> Result<String, String> + Send + Sync>;
+
+#[derive(Clone)]
+pub(crate) struct Custom {
+    pub help: String,
+    pub handler: Handler,
+}
+
+/// The commands registered by the library user, by name.
+#[derive(Clone, Default)]
+pub(crate) struct Commands {
+    custom: BTreeMap<String, Custom>,
+}
+
+impl Commands {
+    pub fn insert(&mut self, name: String, custom: Custom) {
+        self.custom.insert(name, custom);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Custom> {
+        self.custom.get(name)
+    }
+
+    /// Returns the first name that is a built-in command or not a single
+    /// word.
+    pub fn invalid_name(&self) -> Option<&str> {
+        self.custom.keys().map(String::as_str).find(|name| {
+            name.is_empty()
+                || name.contains(|c: char| c == '/' || c.is_whitespace())
+                || builtin(name).is_some()
+        })
+    }
+
+    /// The `/help` text: one line per command, or the line for `topic`.
+    pub fn help(&self, topic: Option<&str>) -> Option<Vec<String>> {
+        let builtins = BUILTINS.iter().map(|builtin| {
+            (
+                builtin.name,
+                format!("{} - {}", builtin.usage, builtin.help),
+            )
+        });
+        let custom = self
+            .custom
+            .iter()
+            .map(|(name, custom)| (name.as_str(), format!("/{name} - {}", custom.help)));
+        let mut lines = builtins.chain(custom);
+        match topic {
+            None => Some(lines.map(|(_, line)| line).collect()),
+            Some(topic) => {
+                let topic = if topic == "list" { "who" } else { topic };
+                lines
+                    .find(|(name, _)| *name == topic)
+                    .map(|(_, line)| vec![line])
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Commands {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.custom.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Command {
+        line.parse().unwrap()
+    }
+
+    #[test]
+    fn commands_take_their_arguments() {
+        assert_eq!(parse("/nick  bob "), Command::Nick("bob".into()));
+        assert_eq!(parse("part"), Command::Part(None));
+        assert_eq!(parse("/part #rust"), Command::Part(Some("#rust".into())));
+        assert_eq!(
+            parse("/msg bob  hello there"),
+            Command::Msg {
+                to: "bob".into(),
+                text: "hello there".into()
+            }
+        );
+        assert_eq!(parse("/list"), Command::Who(None));
+        assert_eq!(parse("/help /join"), Command::Help(Some("join".into())));
+        assert_eq!(
+            parse("/quit gone fishing"),
+            Command::Quit(Some("gone fishing".into()))
+        );
+    }
+
+    #[test]
+    fn join_can_replay_from_an_id_or_a_time() {
+        assert_eq!(
+            parse("/join #rust"),
+            Command::Join {
+                room: "#rust".into(),
+                since: None
+            }
+        );
+        assert_eq!(
+            parse("/join #rust 42"),
+            Command::Join {
+                room: "#rust".into(),
+                since: Some(Since::Id(42))
+            }
+        );
+        assert_eq!(
+            parse("/join #rust @1700000000000"),
+            Command::Join {
+                room: "#rust".into(),
+                since: Some(Since::Ts(1_700_000_000_000))
+            }
+        );
+        assert!(matches!(
+            "/join #rust yesterday".parse::<Command>(),
+            Err(CommandError::Since(_))
+        ));
+    }
+
+    #[test]
+    fn sanctions_take_an_optional_duration_before_the_reason() {
+        assert_eq!(
+            parse("/mute bob 10m stop it"),
+            Command::Mute {
+                nick: "bob".into(),
+                duration: Some(Duration::from_secs(600)),
+                reason: Some("stop it".into())
+            }
+        );
+        assert_eq!(
+            parse("/mute bob stop it"),
+            Command::Mute {
+                nick: "bob".into(),
+                duration: None,
+                reason: Some("stop it".into())
+            }
+        );
+        assert_eq!(
+            parse("/ban 10.0.0.0/8 1d"),
+            Command::Ban {
+                target: BanTarget::Net("10.0.0.0/8".parse().unwrap()),
+                duration: Some(Duration::from_secs(86_400)),
+                reason: None
+            }
+        );
+    }
+
+    #[test]
+    fn missing_arguments_show_the_usage() {
+        assert_eq!("/".parse::<Command>(), Err(CommandError::Empty));
+        assert_eq!(
+            "/nick".parse::<Command>(),
+            Err(CommandError::Usage("/nick <name>"))
+        );
+        assert_eq!(
+            "/msg bob".parse::<Command>(),
+            Err(CommandError::Usage("/msg <nick> <text>"))
+        );
+        assert_eq!(
+            "/kick".parse::<Command>(),
+            Err(CommandError::Usage("/kick <nick> [reason]"))
+        );
+    }
+
+    #[test]
+    fn unknown_commands_are_left_to_custom_handlers() {
+        assert_eq!(
+            parse("/roll  2d6 "),
+            Command::Custom {
+                name: "roll".into(),
+                args: "2d6".into()
+            }
+        );
+    }
+}
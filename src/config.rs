@@ -0,0 +1,331 @@
+use std::{
+    collections::HashSet,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    accounts::AuthConfig,
+    admin::AdminConfig,
+    admission::AdmissionConfig,
+    heartbeat::Heartbeat,
+    history::HistoryConfig,
+    lag::LagPolicy,
+    limit::RateLimit,
+    metrics::MetricsConfig,
+    moderation::ModerationConfig,
+    protocol::DEFAULT_MAX_FRAME_LEN,
+    rooms::{self, RoomError},
+    server::{DEFAULT_CHANNEL_CAPACITY, DEFAULT_SHUTDOWN_TIMEOUT},
+    tls::{self, rustls, TlsError},
+};
+
+pub const DEFAULT_PORT: u16 = 8080;
+pub const DEFAULT_WEBSOCKET_PORT: u16 = 8081;
+pub const DEFAULT_TLS_PORT: u16 = 8443;
+pub const DEFAULT_ROOM: &str = "#lobby";
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+
+/// Server settings, loaded from an optional TOML file and overridden from
+/// the command line.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses to listen on. Entries may be `ip`, `ip:port` or
+    /// `[ipv6]:port`; entries without a port use `port`.
+    pub listen: Vec<String>,
+    /// Port used by `listen` entries that don't name one.
+    pub port: u16,
+    /// Messages buffered per client before it starts lagging.
+    pub channel_capacity: usize,
+    pub lag: LagPolicy,
+    pub heartbeat: Heartbeat,
+    pub admission: AdmissionConfig,
+    /// Longest line, frame or WebSocket message accepted from a client, in
+    /// bytes.
+    pub max_message_len: usize,
+    pub rate_limit: RateLimit,
+    pub history: HistoryConfig,
+    pub auth: AuthConfig,
+    pub moderation: ModerationConfig,
+    /// Room every client joins after registering. Empty to join none.
+    pub default_room: String,
+    /// Seconds to wait for clients to be sent what is queued for them when
+    /// shutting down.
+    pub shutdown_timeout_secs: u64,
+    pub websocket: WebSocketConfig,
+    pub tls: TlsConfig,
+    pub admin: AdminConfig,
+    pub metrics: MetricsConfig,
+    pub log: LogConfig,
+}
+
+/// WebSocket listeners. Their clients share rooms with the TCP ones.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketConfig {
+    /// Addresses to accept WebSocket connections on, in the same format as
+    /// the top-level `listen`. Empty disables WebSockets.
+    pub listen: Vec<String>,
+    pub port: u16,
+    /// Serve `wss://` using the certificate from `[tls]`.
+    pub tls: bool,
+}
+
+/// TLS termination. TLS listeners speak the same protocols as the plain TCP
+/// ones.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain.
+    pub cert: Option<PathBuf>,
+    /// PEM private key for `cert`.
+    pub key: Option<PathBuf>,
+    /// PEM CA certificates. When set, clients must present a certificate
+    /// signed by one of them.
+    pub client_ca: Option<PathBuf>,
+    /// Addresses to accept TLS connections on, in the same format as the
+    /// top-level `listen`.
+    pub listen: Vec<String>,
+    pub port: u16,
+}
+
+/// What the `msg_server` binary logs, and how.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// A level such as `info`, or per-module directives such as
+    /// `warn,msg_server=debug`.
+    pub level: String,
+    /// Write one JSON object per event instead of plain text.
+    pub json: bool,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            listen: Vec::new(),
+            port: DEFAULT_WEBSOCKET_PORT,
+            tls: false,
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            cert: None,
+            key: None,
+            client_ca: None,
+            listen: Vec::new(),
+            port: DEFAULT_TLS_PORT,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: DEFAULT_LOG_LEVEL.to_string(),
+            json: false,
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Loads the certificate and key, or returns `None` if they aren't
+    /// configured.
+    pub fn load(&self) -> Result<Option<Arc<rustls::ServerConfig>>, ConfigError> {
+        let (Some(cert), Some(key)) = (&self.cert, &self.key) else {
+            return Ok(None);
+        };
+        let config = tls::load_server_config(cert, key, self.client_ca.as_deref())?;
+        Ok(Some(config))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("failed to read config file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("no listen addresses configured")]
+    NoListeners,
+    #[error("invalid listen address `{0}`: expected `ip`, `ip:port` or `[ipv6]:port`")]
+    InvalidListenAddr(String),
+    #[error("listen address {0} is configured more than once")]
+    DuplicateListenAddr(SocketAddr),
+    #[error("channel_capacity must be greater than zero")]
+    ZeroChannelCapacity,
+    #[error("heartbeat intervals and timeouts must be greater than zero")]
+    ZeroHeartbeat,
+    #[error("admission limits must be greater than zero")]
+    ZeroAdmissionLimit,
+    #[error("max_message_len must be greater than zero")]
+    ZeroMaxMessageLen,
+    #[error("history.max_messages must be greater than zero")]
+    ZeroMaxMessages,
+    #[error("lag.disconnect_after must be greater than zero")]
+    ZeroDisconnectAfter,
+    #[error("rate_limit rates and bursts must be greater than zero")]
+    InvalidRateLimit,
+    #[error("TLS listeners need both tls.cert and tls.key")]
+    MissingTlsCertificate,
+    #[error(transparent)]
+    Tls(#[from] TlsError),
+    #[error("invalid default_room: {0}")]
+    InvalidDefaultRoom(RoomError),
+    #[error("moderation.operators needs auth.accounts")]
+    OperatorsWithoutAccounts,
+    #[error("invalid log level `{0}`")]
+    InvalidLogLevel(String),
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: vec!["0.0.0.0".to_string()],
+            port: DEFAULT_PORT,
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            lag: LagPolicy::default(),
+            heartbeat: Heartbeat::default(),
+            admission: AdmissionConfig::default(),
+            max_message_len: DEFAULT_MAX_FRAME_LEN,
+            rate_limit: RateLimit::default(),
+            history: HistoryConfig::default(),
+            auth: AuthConfig::default(),
+            moderation: ModerationConfig::default(),
+            default_room: DEFAULT_ROOM.to_string(),
+            shutdown_timeout_secs: DEFAULT_SHUTDOWN_TIMEOUT.as_secs(),
+            websocket: WebSocketConfig::default(),
+            tls: TlsConfig::default(),
+            admin: AdminConfig::default(),
+            metrics: MetricsConfig::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&text).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Resolves `listen` into socket addresses, rejecting malformed and
+    /// duplicate entries.
+    pub fn listen_addrs(&self) -> Result<Vec<SocketAddr>, ConfigError> {
+        resolve(&self.listen, self.port)
+    }
+
+    /// Resolves `websocket.listen` like [`listen_addrs`](Self::listen_addrs).
+    pub fn websocket_addrs(&self) -> Result<Vec<SocketAddr>, ConfigError> {
+        resolve(&self.websocket.listen, self.websocket.port)
+    }
+
+    /// Resolves `tls.listen` like [`listen_addrs`](Self::listen_addrs).
+    pub fn tls_addrs(&self) -> Result<Vec<SocketAddr>, ConfigError> {
+        resolve(&self.tls.listen, self.tls.port)
+    }
+
+    /// Resolves `metrics.listen` like [`listen_addrs`](Self::listen_addrs).
+    pub fn metrics_addrs(&self) -> Result<Vec<SocketAddr>, ConfigError> {
+        resolve(&self.metrics.listen, self.metrics.port)
+    }
+
+    /// The normalized `default_room`, if there is one.
+    pub fn default_room(&self) -> Result<Option<String>, ConfigError> {
+        if self.default_room.is_empty() {
+            return Ok(None);
+        }
+        rooms::normalize(&self.default_room)
+            .map(Some)
+            .map_err(ConfigError::InvalidDefaultRoom)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let tcp = self.listen_addrs()?;
+        let websocket = self.websocket_addrs()?;
+        let tls = self.tls_addrs()?;
+        if tcp.is_empty() && websocket.is_empty() && tls.is_empty() {
+            return Err(ConfigError::NoListeners);
+        }
+        let metrics = self.metrics_addrs()?;
+        let mut seen = HashSet::new();
+        if let Some(&addr) = [tcp, websocket, tls.clone(), metrics]
+            .iter()
+            .flatten()
+            .find(|&&addr| !seen.insert(addr))
+        {
+            return Err(ConfigError::DuplicateListenAddr(addr));
+        }
+
+        let needs_tls =
+            !tls.is_empty() || (self.websocket.tls && !self.websocket.listen.is_empty());
+        if needs_tls && (self.tls.cert.is_none() || self.tls.key.is_none()) {
+            return Err(ConfigError::MissingTlsCertificate);
+        }
+        if self.channel_capacity == 0 {
+            return Err(ConfigError::ZeroChannelCapacity);
+        }
+        self.lag.validate()?;
+        self.heartbeat.validate()?;
+        self.history.validate()?;
+        self.admission.validate()?;
+        if self.max_message_len == 0 {
+            return Err(ConfigError::ZeroMaxMessageLen);
+        }
+        self.rate_limit.validate()?;
+        if !self.moderation.operators.is_empty() && self.auth.accounts.is_none() {
+            return Err(ConfigError::OperatorsWithoutAccounts);
+        }
+        self.default_room()?;
+        Ok(())
+    }
+}
+
+fn resolve(entries: &[String], port: u16) -> Result<Vec<SocketAddr>, ConfigError> {
+    let mut seen = HashSet::new();
+    let mut addrs = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let addr = parse_listen_addr(entry, port)?;
+        if !seen.insert(addr) {
+            return Err(ConfigError::DuplicateListenAddr(addr));
+        }
+        addrs.push(addr);
+    }
+    Ok(addrs)
+}
+
+fn parse_listen_addr(entry: &str, default_port: u16) -> Result<SocketAddr, ConfigError> {
+    let entry = entry.trim();
+    if let Ok(addr) = entry.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+
+    let host = entry
+        .strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+        .unwrap_or(entry);
+    host.parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, default_port))
+        .map_err(|_| ConfigError::InvalidListenAddr(entry.to_string()))
+}
@@ -0,0 +1,1284 @@
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, SystemTime},
+};
+
+use futures_util::FutureExt;
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::{mpsc, watch},
+    time::{self, MissedTickBehavior},
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::{StreamExt, StreamMap};
+
+use crate::{
+    accounts::AccountError,
+    admission::{Refusal, RefusalTicket, Ticket},
+    channel::{Channel, Delivery, Subscription},
+    commands::{Command, Invocation},
+    error::{ConnectionError, Error},
+    heartbeat::{Beat, Liveness},
+    lag::LagTracker,
+    limit::{Flood, Verdict},
+    metrics::Counted,
+    moderation::{Ban, BanTarget, Sanction, DEFAULT_MUTE},
+    protocol::{self, Message, Since, VERSION},
+    rooms,
+    server::{self, Endpoint, Peer, PeerStats, ServerHandle, Shared},
+    users::INBOX_CAPACITY,
+    wire::{Mode, Reply, Sink, Source, WireReader, WireWriter},
+    ws,
+};
+
+/// Failed logins or registrations before a client is disconnected.
+const MAX_LOGIN_ATTEMPTS: u32 = 3;
+
+/// Oversized messages after which a client is disconnected.
+const MAX_OVERSIZED: u32 = 3;
+
+const SHUTDOWN_NOTICE: &str = "server is shutting down";
+
+const LAGGED: &str = "too far behind";
+
+/// The reason given when a client leaves without saying why.
+const QUIT: &str = "quit";
+
+const LOGIN_PROMPT: &str = "/login <name> <password> or /register <name> <password>";
+
+/// How long a refused client has to complete its handshake and be told
+/// why it was refused.
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a client has to complete its TLS or WebSocket handshake,
+/// unless the idle timeout is shorter.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves an admitted connection until it closes. `_ticket` counts it
+/// against the connection limits meanwhile.
+pub(crate) async fn handle(
+    socket: TcpStream,
+    addr: SocketAddr,
+    endpoint: Endpoint,
+    shared: Arc<Shared>,
+    _ticket: Ticket,
+) {
+    shared.peers.lock().unwrap().insert(
+        addr,
+        Peer {
+            endpoint,
+            connected_at: SystemTime::now(),
+            stats: Arc::default(),
+        },
+    );
+    shared.hooks.connected(addr);
+    tracing::info!("accepted");
+
+    let socket = Counted::new(socket, &shared.metrics);
+    let result = if endpoint.is_tls() {
+        let config = shared
+            .tls
+            .clone()
+            .expect("TLS endpoints require a TLS config");
+        let accept = async {
+            TlsAcceptor::from(config)
+                .accept(socket)
+                .await
+                .map_err(ConnectionError::Tls)
+        };
+        match handshake(&shared, accept).await {
+            Ok(Some(stream)) => serve(stream, addr, endpoint, &shared).await,
+            Ok(None) => Ok(()),
+            Err(err) => Err(err),
+        }
+    } else {
+        serve(socket, addr, endpoint, &shared).await
+    };
+    if let Err(source) = result {
+        shared
+            .hooks
+            .error(&Error::Connection { peer: addr, source });
+    }
+
+    shared.rooms.part_all(addr);
+    shared.users.release(addr);
+    shared.hooks.disconnected(addr);
+    tracing::info!("closed");
+    let mut peers = shared.peers.lock().unwrap();
+    peers.remove(&addr);
+    if peers.is_empty() {
+        shared.idle.notify_waiters();
+    }
+}
+
+/// Runs a handshake, giving up if it takes too long. Returns `None` if the
+/// server shuts down first.
+async fn handshake<T>(
+    shared: &Shared,
+    handshake: impl Future<Output = Result<T, ConnectionError>>,
+) -> Result<Option<T>, ConnectionError> {
+    let mut shutdown = shared.shutdown.subscribe();
+    let timeout = shared
+        .heartbeat
+        .idle_timeout()
+        .map_or(HANDSHAKE_TIMEOUT, |idle| idle.min(HANDSHAKE_TIMEOUT));
+    tokio::select! {
+        result = time::timeout(timeout, handshake) => {
+            result.map_err(|_| ConnectionError::HandshakeTimeout)?.map(Some)
+        }
+        _ = server::stopped(&mut shutdown) => Ok(None),
+    }
+}
+
+/// Tells a refused client why, in whatever protocol it speaks, and closes
+/// the connection.
+pub(crate) async fn refuse(
+    socket: TcpStream,
+    endpoint: Endpoint,
+    shared: Arc<Shared>,
+    refusal: Refusal,
+    _ticket: RefusalTicket,
+) {
+    let text = refusal.to_string();
+    let refuse = async {
+        if endpoint.is_tls() {
+            let config = shared
+                .tls
+                .clone()
+                .expect("TLS endpoints require a TLS config");
+            let stream = TlsAcceptor::from(config)
+                .accept(socket)
+                .await
+                .map_err(ConnectionError::Tls)?;
+            explain(stream, endpoint, &shared, &text).await
+        } else {
+            explain(socket, endpoint, &shared, &text).await
+        }
+    };
+    // Refused clients aren't worth reporting errors for.
+    let _ = time::timeout(REFUSE_TIMEOUT, refuse).await;
+}
+
+async fn explain<S>(
+    stream: S,
+    endpoint: Endpoint,
+    shared: &Shared,
+    text: &str,
+) -> Result<(), ConnectionError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    if endpoint.is_websocket() {
+        let (_, mut sink) = ws::accept(stream, shared.max_message_len).await?;
+        sink.error(text).await?;
+        return sink.close().await;
+    }
+    let (read, write) = io::split(stream);
+    let mode = WireReader::new(read, shared.max_message_len)
+        .detect()
+        .await
+        .unwrap_or(Mode::Framed);
+    let mut writer = WireWriter::new(write, mode);
+    writer.error(text).await?;
+    writer.close().await
+}
+
+async fn serve<S>(
+    stream: S,
+    addr: SocketAddr,
+    endpoint: Endpoint,
+    shared: &Arc<Shared>,
+) -> Result<(), ConnectionError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    if endpoint.is_websocket() {
+        serve_websocket(stream, addr, shared).await
+    } else {
+        serve_stream(stream, addr, shared).await
+    }
+}
+
+/// Serves a client speaking plain text or the framed protocol.
+async fn serve_stream<S>(
+    stream: S,
+    addr: SocketAddr,
+    shared: &Arc<Shared>,
+) -> Result<(), ConnectionError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let (read, write) = io::split(stream);
+    let mut reader = WireReader::new(read, shared.max_message_len);
+
+    let (mode, result) = match reader.detect().await {
+        Ok(mode) => (mode, Ok(())),
+        // Most likely a framed client with the wrong version; answer in
+        // kind so it can tell what went wrong.
+        Err(err) => (Mode::Framed, Err(err)),
+    };
+    let mut writer = WireWriter::new(write, mode);
+
+    let result = match result {
+        Ok(()) => session(&mut reader, &mut writer, addr, shared).await,
+        Err(err) => Err(err),
+    };
+    report(result, &mut writer).await
+}
+
+async fn serve_websocket<S>(
+    stream: S,
+    addr: SocketAddr,
+    shared: &Arc<Shared>,
+) -> Result<(), ConnectionError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let accept = ws::accept(stream, shared.max_message_len);
+    let Some((mut source, mut sink)) = handshake(shared, accept).await? else {
+        return Ok(());
+    };
+    let result = session(&mut source, &mut sink, addr, shared).await;
+    report(result, &mut sink).await
+}
+
+/// Tells the client when it is being dropped for something it sent, then
+/// closes the connection.
+async fn report<W: Sink>(
+    result: Result<(), ConnectionError>,
+    writer: &mut W,
+) -> Result<(), ConnectionError> {
+    if let Err(err) = &result {
+        if err.is_client_error() {
+            let _ = writer.error(err.to_string()).await;
+        }
+    }
+    // The client may already be gone, which is not worth reporting.
+    let _ = writer.close().await;
+    result
+}
+
+async fn session<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    addr: SocketAddr,
+    shared: &Arc<Shared>,
+) -> Result<(), ConnectionError>
+where
+    R: Source,
+    W: Sink,
+{
+    let mut shutdown = shared.shutdown.subscribe();
+    let (inbox_tx, mut inbox) = mpsc::channel(INBOX_CAPACITY);
+    let (sanctions_tx, mut sanctions) = mpsc::unbounded_channel();
+    let registered = register(
+        reader,
+        writer,
+        addr,
+        shared,
+        inbox_tx,
+        sanctions_tx,
+        &mut shutdown,
+    );
+    let Some(nick) = registered.await? else {
+        return Ok(());
+    };
+    tracing::Span::current().record("nick", tracing::field::display(&nick));
+    tracing::info!("registered");
+
+    let mut system = shared.channel.subscribe();
+    let operator = shared.accounts.is_some() && shared.moderation.is_operator(&nick);
+    let stats = shared
+        .peers
+        .lock()
+        .unwrap()
+        .get(&addr)
+        .map(|peer| peer.stats.clone())
+        .unwrap_or_default();
+    let mut session = Session {
+        addr,
+        shared,
+        nick,
+        operator,
+        active: None,
+        rooms: StreamMap::new(),
+        lags: LagTracker::default(),
+        flood: Flood::new(addr.ip(), &shared.limiter),
+        oversized: 0,
+        // Text clients can't answer pings, so they are never idle.
+        liveness: Liveness::new(shared.heartbeat.idle_timeout().filter(|_| writer.pings())),
+        quitting: None,
+        stats,
+    };
+    session.online();
+    if let Some(room) = &shared.default_room {
+        session.join(room, None, writer).await?;
+    }
+
+    let result = session
+        .run(
+            reader,
+            writer,
+            &mut system,
+            &mut inbox,
+            &mut sanctions,
+            &mut shutdown,
+        )
+        .await;
+    let reason = match &result {
+        Ok(reason) => reason.clone(),
+        Err(err) => err.to_string(),
+    };
+    tracing::info!(%reason, "quit");
+    session.quit(&reason);
+    result.map(drop)
+}
+
+/// Asks the client for nicknames until it claims a free one, or, when
+/// accounts are enabled, until it logs in. Returns `None` if the client
+/// leaves, fails to log in too often or the server shuts down first.
+async fn register<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    addr: SocketAddr,
+    shared: &Shared,
+    inbox: mpsc::Sender<Message>,
+    sanctions: mpsc::UnboundedSender<Sanction>,
+    shutdown: &mut watch::Receiver<bool>,
+) -> Result<Option<String>, ConnectionError>
+where
+    R: Source,
+    W: Sink,
+{
+    writer.send(&Message::Hello { version: VERSION }).await?;
+    let prompt = match shared.accounts {
+        Some(_) => LOGIN_PROMPT,
+        None => "choose a nickname:",
+    };
+    writer.system(format!("welcome! {prompt}")).await?;
+    let mut failures = 0;
+    loop {
+        let next = async {
+            match shared.heartbeat.idle_timeout() {
+                Some(timeout) => time::timeout(timeout, reader.next())
+                    .await
+                    .map_err(|_| ConnectionError::IdleTimeout)?,
+                None => reader.next().await,
+            }
+        };
+        let msg = tokio::select! {
+            msg = next => msg?,
+            _ = server::stopped(shutdown) => {
+                writer.system(SHUTDOWN_NOTICE).await?;
+                return Ok(None);
+            }
+        };
+        let Some(msg) = msg else {
+            return Ok(None);
+        };
+        let nick = match &shared.accounts {
+            Some(accounts) => {
+                let Some((register, user, password)) = credentials(msg) else {
+                    writer
+                        .error(format!("log in first: {LOGIN_PROMPT}"))
+                        .await?;
+                    continue;
+                };
+                let result = if register {
+                    accounts.register(&user, &password).await
+                } else {
+                    accounts.login(&user, &password).await
+                };
+                match result {
+                    Ok(name) => name,
+                    Err(err) => {
+                        if let AccountError::Store(source) = &err {
+                            shared.hooks.error(source);
+                        }
+                        tracing::warn!(%user, "login failed: {err}");
+                        writer.error(err.to_string()).await?;
+                        failures += 1;
+                        if failures == MAX_LOGIN_ATTEMPTS {
+                            writer
+                                .system("disconnected: too many failed logins")
+                                .await?;
+                            return Ok(None);
+                        }
+                        continue;
+                    }
+                }
+            }
+            // Accept a framed `Nick`, a bare name or `/nick name`.
+            None => match msg {
+                Message::Nick { nick, .. } => nick,
+                Message::Chat { text, .. } => text
+                    .strip_prefix("/nick ")
+                    .unwrap_or(&text)
+                    .trim()
+                    .to_string(),
+                _ => {
+                    writer.error("choose a nickname first").await?;
+                    continue;
+                }
+            },
+        };
+
+        if shared.moderation.banned_nick(&nick).is_some() {
+            writer.error(format!("{nick} is banned")).await?;
+            return Ok(None);
+        }
+        match shared
+            .users
+            .register(addr, &nick, inbox.clone(), sanctions.clone())
+        {
+            Ok(()) => {
+                writer
+                    .send(&Message::Nick {
+                        nick: nick.clone(),
+                        old: None,
+                    })
+                    .await?;
+                return Ok(Some(nick));
+            }
+            Err(err) => {
+                writer.error(err.to_string()).await?;
+                writer.system(prompt).await?;
+            }
+        }
+    }
+}
+
+/// Extracts a login or registration from a framed message or a
+/// `/login name password` or `/register name password` line. The flag is
+/// set for registrations.
+fn credentials(msg: Message) -> Option<(bool, String, String)> {
+    match msg {
+        Message::Login { user, password } => Some((false, user, password)),
+        Message::Register { user, password } => Some((true, user, password)),
+        Message::Chat { text, .. } => {
+            let (command, rest) = text.strip_prefix('/')?.split_once(' ')?;
+            let register = match command {
+                "login" => false,
+                "register" => true,
+                _ => return None,
+            };
+            let (user, password) = rest.trim().split_once(' ')?;
+            Some((register, user.to_string(), password.trim().to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// A registered client's state.
+struct Session<'a> {
+    addr: SocketAddr,
+    shared: &'a Arc<Shared>,
+    nick: String,
+    operator: bool,
+    /// The room plain lines are sent to.
+    active: Option<String>,
+    rooms: StreamMap<String, Subscription>,
+    lags: LagTracker,
+    flood: Flood,
+    /// Oversized messages the client has sent.
+    oversized: u32,
+    liveness: Liveness,
+    /// Set when the client asks to leave, with its reason.
+    quitting: Option<String>,
+    stats: Arc<PeerStats>,
+}
+
+impl Session<'_> {
+    /// Serves the client until it leaves or has to be disconnected. Returns
+    /// the reason given to the other members of its rooms.
+    async fn run<R, W>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut W,
+        system: &mut Subscription,
+        inbox: &mut mpsc::Receiver<Message>,
+        sanctions: &mut mpsc::UnboundedReceiver<Sanction>,
+        shutdown: &mut watch::Receiver<bool>,
+    ) -> Result<String, ConnectionError>
+    where
+        R: Source,
+        W: Sink,
+    {
+        let heartbeat = &self.shared.heartbeat;
+        let mut ticks = time::interval_at(
+            time::Instant::now() + heartbeat.interval(),
+            heartbeat.interval(),
+        );
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                msg = reader.next() => {
+                    let msg = match msg {
+                        Err(err @ ConnectionError::TooLarge { .. }) => {
+                            self.oversized += 1;
+                            if self.oversized >= MAX_OVERSIZED || !reader.skips_oversized() {
+                                return Err(err);
+                            }
+                            writer.error(format!("{err}; message dropped")).await?;
+                            continue;
+                        }
+                        msg => msg?,
+                    };
+                    let Some(msg) = msg else {
+                        return Ok(QUIT.to_string());
+                    };
+                    self.liveness.seen();
+                    if !matches!(msg, Message::Pong { .. }) {
+                        self.stats.messages.fetch_add(1, Ordering::Relaxed);
+                        self.shared.metrics.received.fetch_add(1, Ordering::Relaxed);
+                    }
+                    self.handle(msg, writer).await?;
+                    if let Some(reason) = self.quitting.take() {
+                        return Ok(reason);
+                    }
+                }
+                Some(delivery) = system.next() => {
+                    if !self.deliver(delivery, writer).await? {
+                        return Ok(LAGGED.to_string());
+                    }
+                }
+                Some((_, delivery)) = self.rooms.next() => {
+                    if !self.deliver(delivery, writer).await? {
+                        return Ok(LAGGED.to_string());
+                    }
+                }
+                Some(msg) = inbox.recv() => writer.send(&msg).await?,
+                Some(sanction) = sanctions.recv() => match sanction {
+                    // Announced by the quit that follows.
+                    Sanction::Remove(notice) => {
+                        tracing::info!("{notice}");
+                        writer.error(format!("you were {notice}")).await?;
+                        return Ok(notice);
+                    }
+                    Sanction::Mute { duration, notice } => {
+                        tracing::info!("{notice}");
+                        self.flood.mute(duration);
+                        self.announce(&notice);
+                        writer.error(format!("you were {notice}")).await?;
+                    }
+                },
+                _ = ticks.tick() => match self.liveness.tick(heartbeat) {
+                    Beat::Nothing => {}
+                    Beat::Ping(token) => writer.send(&Message::Ping { token }).await?,
+                    Beat::TimedOut => return Err(ConnectionError::IdleTimeout),
+                },
+                _ = server::stopped(shutdown) => {
+                    self.drain(system, inbox, writer).await?;
+                    writer.system(SHUTDOWN_NOTICE).await?;
+                    return Ok(SHUTDOWN_NOTICE.to_string());
+                }
+            }
+        }
+    }
+
+    /// Tells the members of the client's rooms that an operator muted it.
+    fn announce(&self, notice: &str) {
+        for (_, subscription) in self.rooms.iter() {
+            self.publish(
+                subscription.channel(),
+                Message::System {
+                    text: format!("{} was {notice}", self.nick),
+                },
+            );
+        }
+    }
+
+    /// Tells everyone that the client connected.
+    fn online(&self) {
+        self.publish(
+            &self.shared.channel,
+            Message::Online {
+                nick: self.nick.clone(),
+            },
+        );
+    }
+
+    /// Tells everyone that the client left, and why.
+    fn quit(&self, reason: &str) {
+        self.publish(
+            &self.shared.channel,
+            Message::Quit {
+                nick: Some(self.nick.clone()),
+                reason: Some(reason.to_string()),
+            },
+        );
+    }
+
+    async fn handle<W>(&mut self, msg: Message, writer: &mut W) -> Result<(), ConnectionError>
+    where
+        W: Sink,
+    {
+        match msg {
+            Message::Chat { id: Some(id), .. } => {
+                let mut reply = Reply { inner: writer, id };
+                self.respond(msg, &mut reply).await
+            }
+            msg => self.respond(msg, writer).await,
+        }
+    }
+
+    /// Acts on a message from the client. Errors go through `writer`, which
+    /// marks them with the message's id if it has one.
+    async fn respond<W>(&mut self, msg: Message, writer: &mut W) -> Result<(), ConnectionError>
+    where
+        W: Sink,
+    {
+        // Keepalives don't count against the rate limit.
+        let verdict = match msg {
+            Message::Ping { .. } | Message::Pong { .. } => Verdict::Allow,
+            _ => self.flood.check(&self.shared.limiter),
+        };
+        match verdict {
+            Verdict::Allow => {}
+            Verdict::Warn => return writer.error("slow down; message dropped").await,
+            Verdict::Mute(duration) => {
+                return writer
+                    .error(format!(
+                        "muted for {}s for flooding; keep it up and you will be disconnected",
+                        duration.as_secs()
+                    ))
+                    .await
+            }
+            Verdict::Disconnect => return Err(ConnectionError::Flooding),
+        }
+        match msg {
+            Message::Chat {
+                id, room, to, text, ..
+            } => {
+                match (to, room) {
+                    (Some(to), _) => self.direct(&to, &text, writer).await?,
+                    (None, Some(room)) => match rooms::normalize(&room) {
+                        Ok(room) => self.say(&room, &text, writer).await?,
+                        Err(err) => writer.error(err.to_string()).await?,
+                    },
+                    (None, None) => match text.strip_prefix('/') {
+                        Some(line) if !line.starts_with('/') => self.command(line, writer).await?,
+                        // `//text` is sent as `/text`.
+                        escaped => match self.active.clone() {
+                            Some(room) => self.say(&room, escaped.unwrap_or(&text), writer).await?,
+                            None if text.is_empty() => {}
+                            None => {
+                                writer
+                                    .error("you are not in a room; /join one first")
+                                    .await?
+                            }
+                        },
+                    },
+                }
+                if let Some(id) = id {
+                    writer.send(&Message::Ack { id }).await?;
+                }
+                Ok(())
+            }
+            Message::Join { room, since, .. } => match rooms::normalize(&room) {
+                Ok(room) => self.join(&room, since, writer).await,
+                Err(err) => writer.error(err.to_string()).await,
+            },
+            Message::Leave { room, .. } => self.part(&room, writer).await,
+            Message::Nick { nick, .. } => self.nick(&nick, writer).await,
+            Message::Quit { reason, .. } => {
+                self.quitting = Some(reason.unwrap_or_else(|| QUIT.to_string()));
+                Ok(())
+            }
+            Message::Ping { token } => writer.send(&Message::Pong { token }).await,
+            // Only needed to keep the connection alive.
+            Message::Pong { .. } => Ok(()),
+            _ => writer.error("unexpected message").await,
+        }
+    }
+
+    /// Runs a slash command. Replies go only to this client.
+    async fn command<W>(&mut self, line: &str, writer: &mut W) -> Result<(), ConnectionError>
+    where
+        W: Sink,
+    {
+        let command = match line.parse::<Command>() {
+            Ok(command) => command,
+            Err(err) => {
+                self.command_failed();
+                return writer.error(err.to_string()).await;
+            }
+        };
+        match command {
+            Command::Help(topic) => self.help(topic.as_deref(), writer).await,
+            Command::Nick(nick) => self.nick(&nick, writer).await,
+            Command::Join { room, since } => match rooms::normalize(&room) {
+                Ok(room) => self.join(&room, since, writer).await,
+                Err(err) => writer.error(err.to_string()).await,
+            },
+            Command::Part(room) => self.part(room.as_deref().unwrap_or(""), writer).await,
+            Command::Msg { to, text } => self.direct(&to, &text, writer).await,
+            Command::Rooms => self.list_rooms(writer).await,
+            Command::Names(room) => self.names(room.as_deref().unwrap_or(""), writer).await,
+            Command::Who(None) => self.who(writer).await,
+            Command::Who(Some(room)) => self.names(&room, writer).await,
+            Command::Quit(reason) => {
+                self.quitting = Some(reason.unwrap_or_else(|| QUIT.to_string()));
+                Ok(())
+            }
+            Command::Oper(password) => self.oper(&password, writer).await,
+            Command::Kick { .. }
+            | Command::Ban { .. }
+            | Command::Unban(_)
+            | Command::Bans
+            | Command::Mute { .. }
+                if !self.operator =>
+            {
+                tracing::warn!(%line, "operator command refused");
+                self.command_failed();
+                writer.error("only operators can do that").await
+            }
+            Command::Kick { nick, reason } => {
+                let notice = notice(format!("kicked by {}", self.nick), reason.clone());
+                match self.shared.users.sanction(&nick, Sanction::Remove(notice)) {
+                    Some(nick) => {
+                        tracing::info!(kicked = %nick, reason = reason.as_deref(), "kick");
+                        writer.system(format!("kicked {nick}")).await
+                    }
+                    None => writer.error(format!("{nick} is not online")).await,
+                }
+            }
+            Command::Ban {
+                target,
+                duration,
+                reason,
+            } => self.ban(target, duration, reason, writer).await,
+            Command::Unban(target) => match self.shared.moderation.unban(&target).await {
+                Ok(true) => {
+                    tracing::info!(%target, "unban");
+                    writer.system(format!("unbanned {target}")).await
+                }
+                Ok(false) => writer.error(format!("{target} is not banned")).await,
+                Err(err) => {
+                    self.shared.hooks.error(&err);
+                    writer.error("could not save the ban list").await
+                }
+            },
+            Command::Bans => self.list_bans(writer).await,
+            Command::Mute {
+                nick,
+                duration,
+                reason,
+            } => {
+                let duration = duration.unwrap_or(DEFAULT_MUTE);
+                let by = format!("muted for {} by {}", brief(duration), self.nick);
+                let sanction = Sanction::Mute {
+                    duration,
+                    notice: notice(by, reason.clone()),
+                };
+                match self.shared.users.sanction(&nick, sanction) {
+                    Some(nick) => {
+                        tracing::info!(
+                            muted = %nick,
+                            duration_secs = duration.as_secs(),
+                            reason = reason.as_deref(),
+                            "mute"
+                        );
+                        writer.system(format!("muted {nick}")).await
+                    }
+                    None => writer.error(format!("{nick} is not online")).await,
+                }
+            }
+            Command::Custom { name, args } => self.custom(&name, &args, writer).await,
+        }
+    }
+
+    async fn oper<W>(&mut self, password: &str, writer: &mut W) -> Result<(), ConnectionError>
+    where
+        W: Sink,
+    {
+        match self.shared.moderation.check_password(password) {
+            _ if self.operator => writer.system("you are already an operator").await,
+            Some(true) => {
+                tracing::info!("became an operator");
+                self.operator = true;
+                writer.system("you are now an operator").await
+            }
+            Some(false) => {
+                tracing::warn!("wrong /oper password");
+                writer.error("wrong password").await
+            }
+            None => writer.error("/oper is disabled").await,
+        }
+    }
+
+    /// Bans `target`, disconnecting the clients it covers other than this
+    /// one.
+    async fn ban<W>(
+        &mut self,
+        target: BanTarget,
+        duration: Option<Duration>,
+        reason: Option<String>,
+        writer: &mut W,
+    ) -> Result<(), ConnectionError>
+    where
+        W: Sink,
+    {
+        let ban = Ban {
+            target: target.clone(),
+            by: self.nick.clone(),
+            reason: reason.clone(),
+            expires: duration
+                .map(|duration| protocol::timestamp().saturating_add(duration.as_millis() as u64)),
+        };
+        if let Err(err) = self.shared.moderation.ban(ban).await {
+            self.shared.hooks.error(&err);
+            return writer.error("could not save the ban list").await;
+        }
+        tracing::info!(
+            %target,
+            duration_secs = duration.map(|duration| duration.as_secs()),
+            reason = reason.as_deref(),
+            "ban"
+        );
+
+        let by = match duration {
+            Some(duration) => format!("banned for {} by {}", brief(duration), self.nick),
+            None => format!("banned by {}", self.nick),
+        };
+        let removed = self.shared.users.sanction_all(
+            |nick, addr| addr != self.addr && target.matches(nick, addr.ip()),
+            Sanction::Remove(notice(by, reason)),
+        );
+        let mut reply = format!("banned {target}");
+        if !removed.is_empty() {
+            reply.push_str(&format!(", disconnecting {}", removed.join(", ")));
+        }
+        writer.system(reply).await
+    }
+
+    async fn list_bans<W>(&mut self, writer: &mut W) -> Result<(), ConnectionError>
+    where
+        W: Sink,
+    {
+        let bans = self.shared.moderation.bans();
+        if bans.is_empty() {
+            return writer.system("nobody is banned").await;
+        }
+        let now = protocol::timestamp();
+        for ban in bans {
+            let mut line = format!("{} by {}", ban.target, ban.by);
+            if let Some(expires) = ban.expires {
+                let left = Duration::from_millis(expires.saturating_sub(now));
+                line.push_str(&format!(" for {}", brief(left)));
+            }
+            if let Some(reason) = ban.reason {
+                line.push_str(&format!(": {reason}"));
+            }
+            writer.system(line).await?;
+        }
+        Ok(())
+    }
+
+    async fn help<W>(&mut self, topic: Option<&str>, writer: &mut W) -> Result<(), ConnectionError>
+    where
+        W: Sink,
+    {
+        let Some(lines) = self.shared.commands.help(topic) else {
+            let topic = topic.unwrap_or_default();
+            return writer.error(format!("no such command /{topic}")).await;
+        };
+        for line in lines {
+            writer.system(line).await?;
+        }
+        Ok(())
+    }
+
+    /// Runs a command registered by the library user.
+    async fn custom<W>(
+        &mut self,
+        name: &str,
+        args: &str,
+        writer: &mut W,
+    ) -> Result<(), ConnectionError>
+    where
+        W: Sink,
+    {
+        let Some(custom) = self.shared.commands.get(name) else {
+            self.command_failed();
+            return writer
+                .error(format!("unknown command /{name}; try /help"))
+                .await;
+        };
+        let server = ServerHandle::new(self.shared.clone());
+        let reply = (custom.handler)(&Invocation {
+            nick: &self.nick,
+            addr: self.addr,
+            room: self.active.as_deref(),
+            args,
+            server: &server,
+        });
+        match reply {
+            Ok(text) if text.is_empty() => Ok(()),
+            Ok(text) => writer.system(text).await,
+            Err(text) => {
+                self.command_failed();
+                if text.is_empty() {
+                    return Ok(());
+                }
+                writer.error(text).await
+            }
+        }
+    }
+
+    /// Counts a command that was invalid, unknown, not allowed or failed.
+    fn command_failed(&self) {
+        let errors = &self.shared.metrics.command_errors;
+        errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sends `text` to `room`, which must be normalized.
+    async fn say<W>(
+        &mut self,
+        room: &str,
+        text: &str,
+        writer: &mut W,
+    ) -> Result<(), ConnectionError>
+    where
+        W: Sink,
+    {
+        if text.is_empty() {
+            return Ok(());
+        }
+        if let Some(left) = self.flood.muted() {
+            return writer
+                .error(format!("you are muted for {}s", left.as_secs() + 1))
+                .await;
+        }
+        let Some((_, subscription)) = self.rooms.iter().find(|(name, _)| name == room) else {
+            return writer.error(format!("you are not in {room}")).await;
+        };
+
+        self.shared.hooks.message(self.addr, text);
+        self.publish(
+            subscription.channel(),
+            Message::Chat {
+                id: None,
+                room: Some(room.to_string()),
+                from: Some(self.nick.clone()),
+                to: None,
+                text: text.to_string(),
+                ts: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Sends `text` straight to the user called `to`.
+    async fn direct<W>(
+        &mut self,
+        to: &str,
+        text: &str,
+        writer: &mut W,
+    ) -> Result<(), ConnectionError>
+    where
+        W: Sink,
+    {
+        if let Some(left) = self.flood.muted() {
+            return writer
+                .error(format!("you are muted for {}s", left.as_secs() + 1))
+                .await;
+        }
+        let msg = Message::Chat {
+            id: None,
+            room: None,
+            from: Some(self.nick.clone()),
+            to: Some(to.to_string()),
+            text: text.to_string(),
+            ts: Some(protocol::timestamp()),
+        };
+        match self.shared.users.send_direct(to, msg) {
+            Ok(()) => Ok(()),
+            Err(err) => writer.error(err.to_string()).await,
+        }
+    }
+
+    async fn nick<W>(&mut self, new: &str, writer: &mut W) -> Result<(), ConnectionError>
+    where
+        W: Sink,
+    {
+        if self.shared.accounts.is_some() {
+            return writer.error("your nickname is your account name").await;
+        }
+        if self.shared.moderation.banned_nick(new).is_some() {
+            return writer.error(format!("{new} is banned")).await;
+        }
+        match self.shared.users.rename(self.addr, new) {
+            Ok(()) if new != self.nick => {
+                writer
+                    .send(&Message::Nick {
+                        nick: new.to_string(),
+                        old: None,
+                    })
+                    .await?;
+                let old = std::mem::replace(&mut self.nick, new.to_string());
+                tracing::Span::current().record("nick", tracing::field::display(new));
+                tracing::info!(%old, "renamed");
+                self.publish(
+                    &self.shared.channel,
+                    Message::Nick {
+                        nick: self.nick.clone(),
+                        old: Some(old),
+                    },
+                );
+                Ok(())
+            }
+            Ok(()) => Ok(()),
+            Err(err) => writer.error(err.to_string()).await,
+        }
+    }
+
+    /// Joins `room`, or switches to it if already joined, and replays its
+    /// history: the messages after `since`, or the most recent ones. `room`
+    /// must be normalized.
+    async fn join<W>(
+        &mut self,
+        room: &str,
+        since: Option<Since>,
+        writer: &mut W,
+    ) -> Result<(), ConnectionError>
+    where
+        W: Sink,
+    {
+        if self.rooms.contains_key(room) {
+            self.active = Some(room.to_string());
+            return writer.system(format!("now talking in {room}")).await;
+        }
+
+        let (subscription, replay) = match self.shared.rooms.join(room, self.addr, since).await {
+            Ok(joined) => joined,
+            Err(err) => {
+                self.shared.hooks.error(&err);
+                return writer.error(format!("could not join {room}")).await;
+            }
+        };
+        self.active = Some(room.to_string());
+        self.publish(
+            subscription.channel(),
+            Message::Join {
+                room: room.to_string(),
+                nick: Some(self.nick.clone()),
+                since: None,
+            },
+        );
+        self.rooms.insert(room.to_string(), subscription);
+        writer
+            .send(&Message::Join {
+                room: room.to_string(),
+                nick: None,
+                since: None,
+            })
+            .await?;
+        for msg in &replay {
+            writer.send(msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Leaves `room`, or the active room if `room` is empty.
+    async fn part<W>(&mut self, room: &str, writer: &mut W) -> Result<(), ConnectionError>
+    where
+        W: Sink,
+    {
+        let Some(room) = self.target_room(room, writer).await? else {
+            return Ok(());
+        };
+        let Some(subscription) = self.rooms.remove(&room) else {
+            return writer.error(format!("you are not in {room}")).await;
+        };
+        self.publish(
+            subscription.channel(),
+            Message::Leave {
+                room: room.clone(),
+                nick: Some(self.nick.clone()),
+                reason: None,
+            },
+        );
+        drop(subscription);
+        self.shared.rooms.part(&room, self.addr);
+
+        if self.active.as_ref() == Some(&room) {
+            self.active = self.rooms.keys().next().cloned();
+        }
+        writer
+            .send(&Message::Leave {
+                room,
+                nick: None,
+                reason: None,
+            })
+            .await
+    }
+
+    async fn list_rooms<W>(&mut self, writer: &mut W) -> Result<(), ConnectionError>
+    where
+        W: Sink,
+    {
+        let rooms = self.shared.rooms.list();
+        if rooms.is_empty() {
+            return writer.system("no rooms are open").await;
+        }
+        let list: Vec<_> = rooms
+            .iter()
+            .map(|(name, members)| format!("{name} ({members})"))
+            .collect();
+        writer.system(format!("rooms: {}", list.join(", "))).await
+    }
+
+    /// Lists the members of `room`, or of the active room if `room` is empty.
+    async fn names<W>(&mut self, room: &str, writer: &mut W) -> Result<(), ConnectionError>
+    where
+        W: Sink,
+    {
+        let Some(room) = self.target_room(room, writer).await? else {
+            return Ok(());
+        };
+        let Some(members) = self.shared.rooms.members(&room) else {
+            return writer.error(format!("{room} does not exist")).await;
+        };
+        let mut nicks: Vec<_> = members
+            .into_iter()
+            .filter_map(|addr| self.shared.users.nick(addr))
+            .collect();
+        nicks.sort_unstable();
+        writer.system(format!("{room}: {}", nicks.join(", "))).await
+    }
+
+    /// Lists everyone online and how long they have been connected.
+    async fn who<W>(&mut self, writer: &mut W) -> Result<(), ConnectionError>
+    where
+        W: Sink,
+    {
+        let now = SystemTime::now();
+        let online: Vec<_> = self
+            .shared
+            .online()
+            .into_iter()
+            .map(|user| {
+                let age = now.duration_since(user.connected_at).unwrap_or_default();
+                format!("{} ({})", user.nick, brief(age))
+            })
+            .collect();
+        writer
+            .system(format!("{} online: {}", online.len(), online.join(", ")))
+            .await
+    }
+
+    /// Normalizes a room argument, defaulting to the active room. Reports
+    /// the problem to the client and returns `None` if there isn't one.
+    async fn target_room<W>(
+        &self,
+        room: &str,
+        writer: &mut W,
+    ) -> Result<Option<String>, ConnectionError>
+    where
+        W: Sink,
+    {
+        if room.is_empty() {
+            if self.active.is_none() {
+                writer.error("you are not in a room").await?;
+            }
+            return Ok(self.active.clone());
+        }
+        match rooms::normalize(room) {
+            Ok(room) => Ok(Some(room)),
+            Err(err) => {
+                writer.error(err.to_string()).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Broadcasts `msg` from this client.
+    fn publish(&self, channel: &Channel, msg: Message) {
+        channel.send(self.addr, msg);
+    }
+
+    /// Writes everything already queued for the client, without waiting
+    /// for more.
+    async fn drain<W>(
+        &mut self,
+        system: &mut Subscription,
+        inbox: &mut mpsc::Receiver<Message>,
+        writer: &mut W,
+    ) -> Result<(), ConnectionError>
+    where
+        W: Sink,
+    {
+        while let Some(Some(delivery)) = system.next().now_or_never() {
+            self.deliver(delivery, writer).await?;
+        }
+        while let Some(Some((_, delivery))) = self.rooms.next().now_or_never() {
+            self.deliver(delivery, writer).await?;
+        }
+        while let Ok(msg) = inbox.try_recv() {
+            writer.send(&msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes a delivery to the client. Returns `false` if the client has
+    /// lagged too often and should be disconnected.
+    async fn deliver<W>(
+        &mut self,
+        delivery: Delivery,
+        writer: &mut W,
+    ) -> Result<bool, ConnectionError>
+    where
+        W: Sink,
+    {
+        match delivery {
+            Delivery::Message(msg) => {
+                if msg.from != self.addr {
+                    writer.send(&msg.msg).await?;
+                    self.shared.metrics.relayed.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Delivery::Lagged { missed, replay } => {
+                self.stats.lags.fetch_add(1, Ordering::Relaxed);
+                self.shared.metrics.lags.fetch_add(1, Ordering::Relaxed);
+                if self.shared.lag.notify && missed > 0 {
+                    writer
+                        .system(format!("you missed {missed} messages"))
+                        .await?;
+                }
+                for msg in replay.iter().filter(|msg| msg.from != self.addr) {
+                    writer.send(&msg.msg).await?;
+                    self.shared.metrics.relayed.fetch_add(1, Ordering::Relaxed);
+                }
+                if self.lags.record(&self.shared.lag) {
+                    writer.system(format!("disconnected: {LAGGED}")).await?;
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Completes "you were ..." with the operator's reason, if any.
+fn notice(by: String, reason: Option<String>) -> String {
+    match reason {
+        Some(reason) => format!("{by}: {reason}"),
+        None => by,
+    }
+}
+
+/// Formats a duration in its largest whole unit, like `45s` or `3h`.
+fn brief(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
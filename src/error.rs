@@ -0,0 +1,87 @@
+use std::{io, net::SocketAddr, path::PathBuf};
+
+use thiserror::Error;
+
+use crate::{admission::Refusal, config::ConfigError, protocol::ProtocolError, rooms::RoomError};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error("no listeners configured")]
+    NoListeners,
+    #[error("TLS listeners configured without a TLS certificate")]
+    TlsNotConfigured,
+    #[error("invalid default room: {0}")]
+    DefaultRoom(RoomError),
+    #[error("cannot register `/{0}`: it is a built-in command or not a single word")]
+    InvalidCommand(String),
+    #[error("message history at {path}: {source}")]
+    History { path: PathBuf, source: io::Error },
+    #[error("account file {path}: {source}")]
+    Accounts { path: PathBuf, source: io::Error },
+    #[error("admin socket {path}: {source}")]
+    Admin { path: PathBuf, source: io::Error },
+    #[error("no way to reload the config was set up")]
+    NoReload,
+    #[error("ban list {path}: {source}")]
+    Bans { path: PathBuf, source: io::Error },
+    #[error("failed to listen on {addr}: {source}")]
+    Bind { addr: SocketAddr, source: io::Error },
+    #[error("failed to accept a connection on {listener}: {source}")]
+    Accept {
+        listener: SocketAddr,
+        source: io::Error,
+    },
+    #[error("{remaining} clients were still connected when the shutdown timeout ran out")]
+    ShutdownTimeout { remaining: usize },
+    #[error("refused connection from {peer}: {reason}")]
+    Refused { peer: SocketAddr, reason: Refusal },
+    #[error("connection from {peer} closed: {source}")]
+    Connection {
+        peer: SocketAddr,
+        source: ConnectionError,
+    },
+}
+
+/// Why a single client connection was closed. These never affect other
+/// connections.
+#[derive(Debug, Error)]
+pub enum ConnectionError {
+    #[error("read failed: {0}")]
+    Read(io::Error),
+    #[error("write failed: {0}")]
+    Write(io::Error),
+    #[error("client sent invalid UTF-8")]
+    InvalidUtf8,
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    #[error("message exceeds the {max} byte limit")]
+    TooLarge { max: usize },
+    #[error("ping timeout")]
+    IdleTimeout,
+    #[error("handshake timed out")]
+    HandshakeTimeout,
+    #[error("disconnected for flooding")]
+    Flooding,
+    #[error("TLS handshake failed: {0}")]
+    Tls(io::Error),
+    #[error("websocket error: {0}")]
+    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+}
+
+impl ConnectionError {
+    /// Whether the error was caused by what the client sent, as opposed to
+    /// the connection failing.
+    pub fn is_client_error(&self) -> bool {
+        matches!(
+            self,
+            ConnectionError::InvalidUtf8
+                | ConnectionError::Protocol(_)
+                | ConnectionError::TooLarge { .. }
+                | ConnectionError::Flooding
+        )
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::config::ConfigError;
+
+/// How the server notices clients that have gone away without closing
+/// their connection.
+///
+/// Framed and WebSocket clients that have been quiet for `interval_secs`
+/// are sent a [`Message::Ping`](crate::protocol::Message::Ping), which they
+/// should answer with a `Pong`. Those that send nothing at all for
+/// `idle_timeout_secs` are disconnected. Plain-text clients can't answer
+/// pings, so once registered they are only dropped when TCP keepalive finds
+/// their connection dead.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Heartbeat {
+    pub interval_secs: u64,
+    /// `None` never disconnects quiet clients.
+    pub idle_timeout_secs: Option<u64>,
+    /// Idle seconds before the OS starts sending TCP keepalive probes.
+    /// `None` leaves keepalive off.
+    pub keepalive_secs: Option<u64>,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            interval_secs: 30,
+            idle_timeout_secs: Some(300),
+            keepalive_secs: Some(60),
+        }
+    }
+}
+
+impl Heartbeat {
+    /// The ping interval, at least a second.
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.max(1))
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout_secs.map(Duration::from_secs)
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        if self.interval_secs == 0
+            || self.idle_timeout_secs == Some(0)
+            || self.keepalive_secs == Some(0)
+        {
+            return Err(ConfigError::ZeroHeartbeat);
+        }
+        Ok(())
+    }
+}
+
+/// What a heartbeat tick calls for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Beat {
+    Nothing,
+    Ping(u64),
+    TimedOut,
+}
+
+/// When a single client was last heard from.
+#[derive(Debug)]
+pub(crate) struct Liveness {
+    last_seen: Instant,
+    next_token: u64,
+    /// How long the client may stay quiet, if there is a limit.
+    idle_timeout: Option<Duration>,
+}
+
+impl Liveness {
+    pub fn new(idle_timeout: Option<Duration>) -> Self {
+        Liveness {
+            last_seen: Instant::now(),
+            next_token: 1,
+            idle_timeout,
+        }
+    }
+
+    /// Records that the client sent something.
+    pub fn seen(&mut self) {
+        self.last_seen = Instant::now();
+    }
+
+    pub fn tick(&mut self, policy: &Heartbeat) -> Beat {
+        let quiet = self.last_seen.elapsed();
+        if self.idle_timeout.is_some_and(|timeout| quiet >= timeout) {
+            Beat::TimedOut
+        } else if quiet >= policy.interval() {
+            let token = self.next_token;
+            self.next_token += 1;
+            Beat::Ping(token)
+        } else {
+            Beat::Nothing
+        }
+    }
+}
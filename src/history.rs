@@ -0,0 +1,379 @@
+//! Per-room message history, kept in memory and optionally in append-only
+//! files on disk so it survives restarts. The files are written by a thread
+//! of their own, so disk I/O never holds up the tasks serving clients.
+
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use serde::Deserialize;
+use tokio::{sync::oneshot, task};
+
+use crate::{
+    config::ConfigError,
+    error::Error,
+    hooks::Hooks,
+    protocol::{self, Message, Since},
+};
+
+pub const DEFAULT_REPLAY: usize = 20;
+pub const DEFAULT_MAX_MESSAGES: usize = 1000;
+
+/// Rewrite a log once it holds this many more lines than it retains, so
+/// pruning doesn't rewrite the file on every message.
+const COMPACT_SLACK: usize = 256;
+
+/// Where room history is kept, for how long, and how much of it clients are
+/// sent when they join.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Directory holding one `<room>.jsonl` log per room. Without one,
+    /// history is kept in memory and lost on restart.
+    pub dir: Option<PathBuf>,
+    /// Messages sent to a client when it joins a room, unless it asks for
+    /// the messages since a given id or time instead.
+    pub replay: usize,
+    /// Messages kept per room. Must be greater than zero.
+    pub max_messages: usize,
+    /// Drop messages older than this.
+    pub max_age_secs: Option<u64>,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            dir: None,
+            replay: DEFAULT_REPLAY,
+            max_messages: DEFAULT_MAX_MESSAGES,
+            max_age_secs: None,
+        }
+    }
+}
+
+impl HistoryConfig {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_messages == 0 {
+            return Err(ConfigError::ZeroMaxMessages);
+        }
+        Ok(())
+    }
+}
+
+/// The history of every room that has been used, loaded on demand.
+#[derive(Debug)]
+pub(crate) struct Store {
+    config: HistoryConfig,
+    logs: Mutex<HashMap<String, Arc<Mutex<Log>>>>,
+    /// Set when history is kept on disk.
+    writer: Option<Writer>,
+}
+
+/// One room's retained messages, oldest first.
+#[derive(Debug)]
+pub(crate) struct Log {
+    /// The file the messages are appended to, if any.
+    file: Option<(PathBuf, Writer)>,
+    entries: VecDeque<Message>,
+    /// Lines in the file, including ones pruned from `entries`.
+    lines: usize,
+    last_id: u64,
+    max_messages: usize,
+    max_age_ms: Option<u64>,
+}
+
+/// Queues file operations for the writer thread.
+#[derive(Debug, Clone)]
+struct Writer(mpsc::Sender<Op>);
+
+/// A file operation, carried out by the writer thread in the order queued.
+#[derive(Debug)]
+enum Op {
+    Append {
+        path: PathBuf,
+        line: Vec<u8>,
+    },
+    /// Replaces the file's contents.
+    Rewrite {
+        path: PathBuf,
+        contents: Vec<u8>,
+    },
+    /// Flushes every file to disk and reports how that went.
+    Sync(oneshot::Sender<Result<(), Error>>),
+}
+
+impl Store {
+    /// Opens the store, creating its directory and starting the writer
+    /// thread if history is kept on disk. Write failures are reported
+    /// through `hooks`.
+    pub fn open(config: HistoryConfig, hooks: Hooks) -> Result<Self, Error> {
+        let writer = match &config.dir {
+            Some(dir) => {
+                let failed = |source| Error::History {
+                    path: dir.clone(),
+                    source,
+                };
+                fs::create_dir_all(dir).map_err(failed)?;
+                let (ops, queued) = mpsc::channel();
+                thread::Builder::new()
+                    .name("history".to_string())
+                    .spawn(move || write_files(queued, &hooks))
+                    .map_err(failed)?;
+                Some(Writer(ops))
+            }
+            None => None,
+        };
+        Ok(Store {
+            config,
+            logs: Mutex::new(HashMap::new()),
+            writer,
+        })
+    }
+
+    pub fn replay(&self) -> usize {
+        self.config.replay
+    }
+
+    /// The log for `room`, which must be normalized, loading it from disk
+    /// the first time.
+    pub async fn log(&self, room: &str) -> Result<Arc<Mutex<Log>>, Error> {
+        if let Some(log) = self.logs.lock().unwrap().get(room) {
+            return Ok(log.clone());
+        }
+
+        let file = match (&self.config.dir, &self.writer) {
+            // Normalized names are `#` and then characters safe in a path.
+            (Some(dir), Some(writer)) => Some((
+                dir.join(format!("{}.jsonl", room.trim_start_matches('#'))),
+                writer.clone(),
+            )),
+            _ => None,
+        };
+        let config = self.config.clone();
+        let mut loaded = task::spawn_blocking(move || Log::open(file, &config))
+            .await
+            .expect("loading a log doesn't panic")?;
+
+        // Another task may have loaded the log meanwhile. Only the copy
+        // kept may touch the file.
+        let mut logs = self.logs.lock().unwrap();
+        let log = logs.entry(room.to_string()).or_insert_with(|| {
+            if loaded.lines > loaded.entries.len() {
+                loaded.compact();
+            }
+            Arc::new(Mutex::new(loaded))
+        });
+        Ok(log.clone())
+    }
+
+    /// Waits for every message logged so far to be written and flushed to
+    /// disk.
+    pub async fn sync(&self) -> Result<(), Error> {
+        let Some(writer) = &self.writer else {
+            return Ok(());
+        };
+        let (done, synced) = oneshot::channel();
+        writer.queue(Op::Sync(done));
+        synced.await.unwrap_or_else(|_| {
+            Err(Error::History {
+                path: self.config.dir.clone().unwrap_or_default(),
+                source: io::Error::other("the history writer stopped"),
+            })
+        })
+    }
+}
+
+impl Log {
+    /// Reads the retained messages from the file, if there is one.
+    fn open(file: Option<(PathBuf, Writer)>, config: &HistoryConfig) -> Result<Self, Error> {
+        let mut log = Log {
+            file,
+            entries: VecDeque::new(),
+            lines: 0,
+            last_id: 0,
+            max_messages: config.max_messages,
+            max_age_ms: config.max_age_secs.map(|secs| secs.saturating_mul(1000)),
+        };
+        if let Some((path, _)) = &log.file {
+            let path = path.clone();
+            log.read(&path)
+                .map_err(|source| Error::History { path, source })?;
+        }
+        log.prune();
+        Ok(log)
+    }
+
+    fn read(&mut self, path: &Path) -> io::Result<()> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            self.lines += 1;
+            // A line cut short by a crash is skipped, not fatal.
+            if let Ok(msg) = serde_json::from_str::<Message>(&line) {
+                self.last_id = self.last_id.max(id(&msg));
+                self.entries.push_back(msg);
+            }
+        }
+        Ok(())
+    }
+
+    /// The id of the newest message ever logged, `0` if there is none.
+    pub fn last_id(&self) -> u64 {
+        self.last_id
+    }
+
+    /// Records a chat message, which must already carry its id, and queues
+    /// it to be written.
+    pub fn append(&mut self, msg: &Message) {
+        self.last_id = id(msg);
+        self.entries.push_back(msg.clone());
+        self.prune();
+
+        let Some((path, writer)) = &self.file else {
+            return;
+        };
+        writer.queue(Op::Append {
+            path: path.clone(),
+            line: line(msg),
+        });
+        self.lines += 1;
+        if self.lines > self.entries.len() + COMPACT_SLACK {
+            self.compact();
+        }
+    }
+
+    /// The last `count` messages, oldest first.
+    pub fn recent(&mut self, count: usize) -> Vec<Message> {
+        self.prune();
+        let skip = self.entries.len().saturating_sub(count);
+        self.entries.iter().skip(skip).cloned().collect()
+    }
+
+    /// The messages after `since`, oldest first.
+    pub fn since(&mut self, since: Since) -> Vec<Message> {
+        self.prune();
+        self.entries
+            .iter()
+            .filter(|msg| match since {
+                Since::Id(since) => id(msg) > since,
+                Since::Ts(since) => ts(msg) > since,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Drops messages the retention policy no longer covers.
+    fn prune(&mut self) {
+        let excess = self.entries.len().saturating_sub(self.max_messages);
+        self.entries.drain(..excess);
+        if let Some(max_age) = self.max_age_ms {
+            let cutoff = protocol::timestamp().saturating_sub(max_age);
+            while self.entries.front().is_some_and(|msg| ts(msg) < cutoff) {
+                self.entries.pop_front();
+            }
+        }
+    }
+
+    /// Queues a rewrite of the file with only the retained messages.
+    fn compact(&mut self) {
+        let Some((path, writer)) = &self.file else {
+            return;
+        };
+        writer.queue(Op::Rewrite {
+            path: path.clone(),
+            contents: self.entries.iter().flat_map(line).collect(),
+        });
+        self.lines = self.entries.len();
+    }
+}
+
+impl Writer {
+    fn queue(&self, op: Op) {
+        // Only fails if the writer thread panicked, leaving nothing to do.
+        let _ = self.0.send(op);
+    }
+}
+
+/// Carries out file operations until every [`Writer`] is gone. Messages
+/// whose writes fail stay in memory.
+fn write_files(ops: mpsc::Receiver<Op>, hooks: &Hooks) {
+    let mut files = HashMap::new();
+    for op in ops {
+        let (path, result) = match op {
+            Op::Append { path, line } => {
+                let result = append(&mut files, &path, &line);
+                (path, result)
+            }
+            Op::Rewrite { path, contents } => {
+                files.remove(&path);
+                let result = rewrite(&path, &contents);
+                (path, result)
+            }
+            Op::Sync(done) => {
+                let result = files
+                    .iter()
+                    .try_for_each(|(path, file): (&PathBuf, &File)| {
+                        file.sync_data().map_err(|source| Error::History {
+                            path: path.clone(),
+                            source,
+                        })
+                    });
+                let _ = done.send(result);
+                continue;
+            }
+        };
+        if let Err(source) = result {
+            // Reopened on the next append.
+            files.remove(&path);
+            hooks.error(&Error::History { path, source });
+        }
+    }
+}
+
+fn append(files: &mut HashMap<PathBuf, File>, path: &Path, line: &[u8]) -> io::Result<()> {
+    let file = match files.entry(path.to_path_buf()) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => {
+            entry.insert(OpenOptions::new().create(true).append(true).open(path)?)
+        }
+    };
+    file.write_all(line)
+}
+
+/// Replaces the file at `path` without ever leaving it half written.
+fn rewrite(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("jsonl.tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_data()?;
+    fs::rename(&tmp, path)
+}
+
+fn line(msg: &Message) -> Vec<u8> {
+    let mut line = serde_json::to_vec(msg).expect("messages always serialize");
+    line.push(b'\n');
+    line
+}
+
+fn id(msg: &Message) -> u64 {
+    match msg {
+        Message::Chat { id, .. } => id.unwrap_or(0),
+        _ => 0,
+    }
+}
+
+fn ts(msg: &Message) -> u64 {
+    match msg {
+        Message::Chat { ts, .. } => ts.unwrap_or(0),
+        _ => 0,
+    }
+}
@@ -0,0 +1,67 @@
+use std::{fmt, net::SocketAddr, sync::Arc};
+
+use crate::{
+    config::{Config, ConfigError},
+    error::Error,
+};
+
+type PeerHook = Arc<dyn Fn(SocketAddr) + Send + Sync>;
+type MessageHook = Arc<dyn Fn(SocketAddr, &str) + Send + Sync>;
+type ErrorHook = Arc<dyn Fn(&Error) + Send + Sync>;
+type ReloadHook = Arc<dyn Fn() -> Result<Config, ConfigError> + Send + Sync>;
+
+/// Callbacks invoked from connection tasks. They run inline, so they should
+/// return quickly.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    pub on_connect: Option<PeerHook>,
+    pub on_message: Option<MessageHook>,
+    pub on_disconnect: Option<PeerHook>,
+    pub on_error: Option<ErrorHook>,
+    /// Loads the config for [`ServerHandle::reload`](crate::ServerHandle::reload).
+    pub reload: Option<ReloadHook>,
+}
+
+impl Hooks {
+    pub fn connected(&self, addr: SocketAddr) {
+        if let Some(hook) = &self.on_connect {
+            hook(addr);
+        }
+    }
+
+    pub fn message(&self, addr: SocketAddr, line: &str) {
+        if let Some(hook) = &self.on_message {
+            hook(addr, line);
+        }
+    }
+
+    pub fn disconnected(&self, addr: SocketAddr) {
+        if let Some(hook) = &self.on_disconnect {
+            hook(addr);
+        }
+    }
+
+    /// Reports an error that the server recovered from. Failed and refused
+    /// connections are logged as warnings, since clients cause most of them.
+    pub fn error(&self, err: &Error) {
+        match err {
+            Error::Connection { .. } | Error::Refused { .. } => tracing::warn!("{err}"),
+            _ => tracing::error!("{err}"),
+        }
+        if let Some(hook) = &self.on_error {
+            hook(err);
+        }
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_connect", &self.on_connect.is_some())
+            .field("on_message", &self.on_message.is_some())
+            .field("on_disconnect", &self.on_disconnect.is_some())
+            .field("on_error", &self.on_error.is_some())
+            .field("reload", &self.reload.is_some())
+            .finish()
+    }
+}
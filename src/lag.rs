@@ -0,0 +1,69 @@
+use std::{collections::VecDeque, time::Duration};
+
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::config::ConfigError;
+
+/// What happens when a client falls so far behind that the broadcast
+/// channel drops messages before it reads them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LagPolicy {
+    /// Tell the client how many messages it missed.
+    pub notify: bool,
+    /// Number of recent messages kept so lagging clients can be resynced.
+    /// `0` disables resyncing.
+    pub history: usize,
+    /// Disconnect a client that lags this many times within `window_secs`.
+    pub disconnect_after: Option<u32>,
+    pub window_secs: u64,
+}
+
+impl Default for LagPolicy {
+    fn default() -> Self {
+        LagPolicy {
+            notify: true,
+            history: 0,
+            disconnect_after: None,
+            window_secs: 60,
+        }
+    }
+}
+
+impl LagPolicy {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        if self.disconnect_after == Some(0) {
+            return Err(ConfigError::ZeroDisconnectAfter);
+        }
+        Ok(())
+    }
+}
+
+/// Counts a single client's recent lag events.
+#[derive(Debug, Default)]
+pub(crate) struct LagTracker {
+    events: VecDeque<Instant>,
+}
+
+impl LagTracker {
+    /// Records a lag event and returns whether the client should now be
+    /// disconnected under `policy`.
+    pub fn record(&mut self, policy: &LagPolicy) -> bool {
+        let Some(limit) = policy.disconnect_after else {
+            return false;
+        };
+
+        let now = Instant::now();
+        let window = Duration::from_secs(policy.window_secs);
+        while self
+            .events
+            .front()
+            .is_some_and(|&at| now.duration_since(at) > window)
+        {
+            self.events.pop_front();
+        }
+        self.events.push_back(now);
+        self.events.len() >= limit as usize
+    }
+}
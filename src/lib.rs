@@ -0,0 +1,64 @@
+//! A line-based broadcast chat server.
+//!
+//! Clients pick a nickname when they connect and can then join any number of
+//! named rooms. Every line they send is relayed to the other members of
+//! their current room as `[#room] <nick> line`. Clients that need more than
+//! text lines can use the framed protocol described in [`protocol`].
+//!
+//! ```no_run
+//! # async fn example() -> msg_server::Result<()> {
+//! let server = msg_server::Server::builder()
+//!     .bind("127.0.0.1:8080".parse().unwrap())
+//!     .build()?;
+//! let handle = server.handle();
+//! tokio::spawn(server.run());
+//! // ...
+//! handle.shutdown();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Connections, errors and moderation are reported as [`tracing`] events,
+//! each connection's inside a `connection` span with its peer address and
+//! nickname. Install a subscriber to see them.
+
+mod accounts;
+pub mod admin;
+mod admission;
+mod channel;
+mod commands;
+pub mod config;
+mod connection;
+mod error;
+mod heartbeat;
+mod history;
+mod hooks;
+mod lag;
+mod limit;
+mod metrics;
+mod moderation;
+pub mod protocol;
+mod rooms;
+mod server;
+pub mod tls;
+mod users;
+mod wire;
+mod ws;
+
+pub use accounts::{AccountError, AuthConfig, MIN_PASSWORD_LEN};
+pub use admission::{AdmissionConfig, Cidr, CidrError, Refusal};
+pub use commands::{Command, CommandError, Invocation};
+pub use config::{Config, ConfigError, LogConfig, TlsConfig, WebSocketConfig};
+pub use error::{ConnectionError, Error, Result};
+pub use heartbeat::Heartbeat;
+pub use history::HistoryConfig;
+pub use lag::LagPolicy;
+pub use limit::RateLimit;
+pub use metrics::{MetricsConfig, DEFAULT_METRICS_PORT};
+pub use moderation::{Ban, BanTarget, BanTargetError, ModerationConfig};
+pub use rooms::{RoomError, MAX_ROOM_NAME_LEN};
+pub use server::{
+    Endpoint, Server, ServerBuilder, ServerHandle, DEFAULT_CHANNEL_CAPACITY,
+    DEFAULT_SHUTDOWN_TIMEOUT,
+};
+pub use users::{NickError, Presence, MAX_NICK_LEN};
@@ -0,0 +1,239 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{Mutex, RwLock},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::config::ConfigError;
+
+/// Drop idle per-IP buckets once this many are tracked. After each sweep
+/// the next one waits until the number left has doubled, so sweeping stays
+/// cheap however many addresses are busy.
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+/// How fast clients may send, and what happens to those that send faster.
+///
+/// Every message a client sends takes a token from its own bucket and from
+/// the bucket shared by all connections from its IP address. A message
+/// that finds either bucket empty is dropped with a warning; after
+/// `mute_after` of those within `window_secs` the client is muted for
+/// `mute_secs`, and after `disconnect_after` mutes it is disconnected.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimit {
+    pub enabled: bool,
+    /// Messages per second a connection may send.
+    pub rate: f64,
+    /// Messages a connection may send at once after being quiet.
+    pub burst: u32,
+    /// Messages per second all connections from one IP may send together.
+    pub ip_rate: f64,
+    pub ip_burst: u32,
+    pub mute_after: u32,
+    pub window_secs: u64,
+    pub mute_secs: u64,
+    pub disconnect_after: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            enabled: true,
+            rate: 5.0,
+            burst: 10,
+            ip_rate: 20.0,
+            ip_burst: 40,
+            mute_after: 3,
+            window_secs: 60,
+            mute_secs: 30,
+            disconnect_after: 3,
+        }
+    }
+}
+
+impl RateLimit {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        if self.enabled
+            && !(self.rate > 0.0 && self.ip_rate > 0.0 && self.burst > 0 && self.ip_burst > 0)
+        {
+            return Err(ConfigError::InvalidRateLimit);
+        }
+        Ok(())
+    }
+}
+
+/// What to do with a message a client just sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Allow,
+    /// Drop the message and warn the client.
+    Warn,
+    /// Drop the message; the client is now muted for this long.
+    Mute(Duration),
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(burst: u32, now: Instant) -> Self {
+        TokenBucket {
+            tokens: f64::from(burst),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, burst: u32, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(f64::from(burst));
+        self.updated = now;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tokens < 1.0
+    }
+}
+
+#[derive(Debug)]
+struct Ips {
+    buckets: HashMap<IpAddr, TokenBucket>,
+    /// Sweep out full buckets once there are this many.
+    sweep_at: usize,
+}
+
+/// The per-IP buckets, shared by every connection.
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    policy: RwLock<RateLimit>,
+    ips: Mutex<Ips>,
+}
+
+impl Limiter {
+    pub fn new(policy: RateLimit) -> Self {
+        Limiter {
+            policy: RwLock::new(policy),
+            ips: Mutex::new(Ips {
+                buckets: HashMap::new(),
+                sweep_at: MAX_IDLE_BUCKETS,
+            }),
+        }
+    }
+
+    pub fn policy(&self) -> RateLimit {
+        self.policy.read().unwrap().clone()
+    }
+
+    /// Applies a new policy. Buckets keep their tokens, up to the new burst.
+    pub fn reconfigure(&self, policy: RateLimit) {
+        *self.policy.write().unwrap() = policy;
+    }
+
+    /// Takes a token from both `own` and `ip`'s bucket, or from neither if
+    /// either is empty.
+    fn take(&self, policy: &RateLimit, ip: IpAddr, own: &mut TokenBucket, now: Instant) -> bool {
+        let &RateLimit {
+            rate,
+            burst,
+            ip_rate,
+            ip_burst,
+            ..
+        } = policy;
+        own.refill(rate, burst, now);
+        let mut ips = self.ips.lock().unwrap();
+        let Ips { buckets, sweep_at } = &mut *ips;
+        if buckets.len() >= *sweep_at {
+            buckets.retain(|_, bucket| {
+                bucket.refill(ip_rate, ip_burst, now);
+                bucket.tokens < f64::from(ip_burst)
+            });
+            *sweep_at = (buckets.len() * 2).max(MAX_IDLE_BUCKETS);
+        }
+        let shared = buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::full(ip_burst, now));
+        shared.refill(ip_rate, ip_burst, now);
+        if own.is_empty() || shared.is_empty() {
+            return false;
+        }
+        own.tokens -= 1.0;
+        shared.tokens -= 1.0;
+        true
+    }
+}
+
+/// One connection's bucket, warnings and mutes.
+#[derive(Debug)]
+pub(crate) struct Flood {
+    ip: IpAddr,
+    bucket: TokenBucket,
+    strikes: VecDeque<Instant>,
+    mutes: u32,
+    muted_until: Option<Instant>,
+}
+
+impl Flood {
+    pub fn new(ip: IpAddr, limiter: &Limiter) -> Self {
+        Flood {
+            ip,
+            bucket: TokenBucket::full(limiter.policy().burst, Instant::now()),
+            strikes: VecDeque::new(),
+            mutes: 0,
+            muted_until: None,
+        }
+    }
+
+    /// Decides what to do with a message the client just sent.
+    pub fn check(&mut self, limiter: &Limiter) -> Verdict {
+        let policy = &limiter.policy();
+        if !policy.enabled {
+            return Verdict::Allow;
+        }
+
+        let now = Instant::now();
+        if limiter.take(policy, self.ip, &mut self.bucket, now) {
+            return Verdict::Allow;
+        }
+
+        let window = Duration::from_secs(policy.window_secs);
+        while self
+            .strikes
+            .front()
+            .is_some_and(|&at| now.duration_since(at) > window)
+        {
+            self.strikes.pop_front();
+        }
+        self.strikes.push_back(now);
+        if self.strikes.len() < policy.mute_after as usize {
+            return Verdict::Warn;
+        }
+
+        self.strikes.clear();
+        self.mutes += 1;
+        if self.mutes >= policy.disconnect_after {
+            return Verdict::Disconnect;
+        }
+        let duration = Duration::from_secs(policy.mute_secs);
+        self.muted_until = Some(now + duration);
+        Verdict::Mute(duration)
+    }
+
+    /// Mutes the client for at least `duration`, whatever its rate.
+    pub fn mute(&mut self, duration: Duration) {
+        let until = Instant::now() + duration;
+        self.muted_until = Some(self.muted_until.map_or(until, |old| old.max(until)));
+    }
+
+    /// How much longer the client is muted for, if it is.
+    pub fn muted(&self) -> Option<Duration> {
+        let left = self.muted_until?.checked_duration_since(Instant::now())?;
+        (!left.is_zero()).then_some(left)
+    }
+}
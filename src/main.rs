@@ -1,45 +1,97 @@
-use tokio::{
-    net::TcpListener, 
-    io::{AsyncWriteExt, BufReader, AsyncBufReadExt}, sync::broadcast
+mod cli;
+
+use std::{
+    io::{self, IsTerminal},
+    process::{self, ExitCode},
 };
 
+use clap::Parser;
+use msg_server::{config::LogConfig, ConfigError, ServerBuilder};
+use tokio::signal;
+use tracing_subscriber::{fmt, EnvFilter};
+
 #[tokio::main]
-async fn main() {
-    let listener = TcpListener::bind("192.168.0.142:8080").await.unwrap(); 
-
-    let (tx,_rx) = broadcast::channel(10);
-
-    loop {
-
-        let tx = tx.clone();
-        let mut rx = tx.subscribe();
-
-        let (mut socket, addr) = listener.accept().await.unwrap();
-        tokio::spawn(async move {
-            let (read, mut writer) = socket.split();
-
-            let mut reader = BufReader::new(read);
-            let mut line = String::new();
-
-            loop {
-                tokio::select! {
-                    result = reader.read_line(&mut line) => {
-                        if result.unwrap() == 0 {
-                            break; 
-                        }
-
-                        tx.send((line.clone(), addr)).unwrap();
-                        line.clear(); 
-                    }
-                    result = rx.recv() => {
-                        let (msg, other_addr) = result.unwrap(); 
-
-                        if addr != other_addr {
-                            writer.write_all(msg.as_bytes()).await.unwrap();
-                        }
-                    }
-                }
-            }
-        });
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        // Errors from before logging was set up can only go to stderr.
+        Err(err) if !tracing::dispatcher::has_been_set() => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+        Err(err) => {
+            tracing::error!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run() -> msg_server::Result<()> {
+    let args = cli::Args::parse();
+    let config = args.clone().load()?;
+    init_logging(&config.log)?;
+    let server = ServerBuilder::from_config(&config)?
+        .reload_with(move || args.clone().load())
+        .build()?;
+
+    let handle = server.handle();
+    tokio::spawn(async move {
+        terminated().await;
+        tracing::info!("interrupted; interrupt again to exit immediately");
+        handle.shutdown();
+        terminated().await;
+        process::exit(130);
+    });
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(server.handle()));
+
+    server.run().await
+}
+
+/// Logs to stderr, and reports panics there too so that they reach the
+/// log shipper in the same format, before the default hook prints them.
+fn init_logging(config: &LogConfig) -> msg_server::Result<()> {
+    let filter = EnvFilter::try_new(&config.level)
+        .map_err(|_| ConfigError::InvalidLogLevel(config.level.clone()))?;
+    let builder = fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal());
+    if config.json {
+        builder.json().with_current_span(true).init();
+    } else {
+        builder.init();
+    }
+    let default = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        tracing::error!("{info}");
+        default(info);
+    }));
+    Ok(())
+}
+
+/// Reloads the config whenever the process gets SIGHUP.
+#[cfg(unix)]
+async fn reload_on_hangup(handle: msg_server::ServerHandle) {
+    let mut sighup = signal::unix::signal(signal::unix::SignalKind::hangup())
+        .expect("failed to install the SIGHUP handler");
+    while sighup.recv().await.is_some() {
+        // `reload` logs the outcome.
+        let _ = handle.reload();
+    }
+}
+
+/// Waits for SIGINT or, on Unix, SIGTERM.
+async fn terminated() {
+    #[cfg(unix)]
+    {
+        let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install the SIGTERM handler");
+        tokio::select! {
+            _ = signal::ctrl_c() => {}
+            _ = sigterm.recv() => {}
+        }
     }
+    #[cfg(not(unix))]
+    let _ = signal::ctrl_c().await;
 }
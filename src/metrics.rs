@@ -0,0 +1,277 @@
+//! Counters exported in the Prometheus text format from an optional
+//! `/metrics` HTTP endpoint.
+
+use std::{
+    fmt::Write as _,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+use crate::{
+    error::Error,
+    server::{self, AcceptBackoff, Shared},
+};
+
+pub const DEFAULT_METRICS_PORT: u16 = 9090;
+
+/// Longest request head a scraper may send.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// How long a scraper has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where metrics are served.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Addresses to serve `/metrics` on, in the same format as the
+    /// top-level `listen`. Empty disables metrics.
+    pub listen: Vec<String>,
+    pub port: u16,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            listen: Vec::new(),
+            port: DEFAULT_METRICS_PORT,
+        }
+    }
+}
+
+/// The server's counters. Gauges are read from the server's state when
+/// scraped.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    pub accepted: AtomicU64,
+    pub refused: AtomicU64,
+    pub received: AtomicU64,
+    pub relayed: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub lags: AtomicU64,
+    pub command_errors: AtomicU64,
+}
+
+/// Renders every metric in the Prometheus text exposition format.
+fn render(shared: &Shared) -> String {
+    let metrics = &shared.metrics;
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+        let _ = writeln!(out, "# HELP msg_server_{name} {help}");
+        let _ = writeln!(out, "# TYPE msg_server_{name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(out, "msg_server_{name}{labels} {value}");
+        }
+    };
+    let counter = |counter: &AtomicU64| vec![(String::new(), counter.load(Ordering::Relaxed))];
+
+    metric(
+        "connections_accepted_total",
+        "counter",
+        "Connections accepted.",
+        &counter(&metrics.accepted),
+    );
+    metric(
+        "connections_refused_total",
+        "counter",
+        "Connections turned away by bans or admission limits.",
+        &counter(&metrics.refused),
+    );
+    let active = shared.peers.lock().unwrap().len() as u64;
+    metric(
+        "connections_active",
+        "gauge",
+        "Connections open now.",
+        &[(String::new(), active)],
+    );
+    metric(
+        "messages_received_total",
+        "counter",
+        "Messages and commands received from clients.",
+        &counter(&metrics.received),
+    );
+    metric(
+        "messages_relayed_total",
+        "counter",
+        "Broadcast messages written to clients.",
+        &counter(&metrics.relayed),
+    );
+    metric(
+        "bytes_received_total",
+        "counter",
+        "Bytes read from client connections.",
+        &counter(&metrics.bytes_in),
+    );
+    metric(
+        "bytes_sent_total",
+        "counter",
+        "Bytes written to client connections.",
+        &counter(&metrics.bytes_out),
+    );
+    metric(
+        "lag_events_total",
+        "counter",
+        "Times a client fell behind a broadcast channel.",
+        &counter(&metrics.lags),
+    );
+    metric(
+        "command_errors_total",
+        "counter",
+        "Slash commands that were invalid, unknown, not allowed or failed.",
+        &counter(&metrics.command_errors),
+    );
+    let rooms: Vec<_> = shared
+        .rooms
+        .list()
+        .into_iter()
+        .map(|(room, members)| (format!("{{room=\"{}\"}}", escape(&room)), members as u64))
+        .collect();
+    metric(
+        "room_members",
+        "gauge",
+        "Members of each open room.",
+        &rooms,
+    );
+    out
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+/// Serves `/metrics` until the server shuts down.
+pub(crate) async fn serve(listener: TcpListener, shared: Arc<Shared>) {
+    let mut shutdown = shared.shutdown.subscribe();
+    let mut backoff = AcceptBackoff::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    backoff.reset();
+                    let shared = shared.clone();
+                    tokio::spawn(async move {
+                        // Scrapers retry; a failed scrape is not worth reporting.
+                        let _ = time::timeout(REQUEST_TIMEOUT, respond(stream, &shared)).await;
+                    });
+                }
+                Err(source) => {
+                    let listener = listener
+                        .local_addr()
+                        .unwrap_or_else(|_| ([0, 0, 0, 0], 0).into());
+                    shared.hooks.error(&Error::Accept { listener, source });
+                    if !backoff.wait(&mut shutdown).await {
+                        return;
+                    }
+                }
+            },
+            _ = server::stopped(&mut shutdown) => return,
+        }
+    }
+}
+
+/// Answers one HTTP/1.x request and closes the connection.
+async fn respond(mut stream: TcpStream, shared: &Shared) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_LEN {
+            return write(&mut stream, "431 Request Header Fields Too Large", "").await;
+        }
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    let line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut parts = line.split(|&b| b == b' ');
+    let (method, path) = (parts.next(), parts.next());
+    let path = path.map(|path| path.split(|&b| b == b'?').next().unwrap_or_default());
+    match (method, path) {
+        (Some(b"GET"), Some(b"/metrics")) => write(&mut stream, "200 OK", &render(shared)).await,
+        (Some(b"GET"), _) => write(&mut stream, "404 Not Found", "").await,
+        _ => write(&mut stream, "405 Method Not Allowed", "").await,
+    }
+}
+
+async fn write(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// A stream that adds the bytes read and written to the metrics.
+pub(crate) struct Counted<'a, S> {
+    inner: S,
+    metrics: &'a Metrics,
+}
+
+impl<'a, S> Counted<'a, S> {
+    pub fn new(inner: S, metrics: &'a Metrics) -> Self {
+        Counted { inner, metrics }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        self.metrics.bytes_in.fetch_add(read, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.metrics
+                .bytes_out
+                .fetch_add(written as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,389 @@
+//! Operators and the bans, kicks and mutes they hand out.
+
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Mutex, RwLock},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{sync::Mutex as AsyncMutex, task};
+
+use crate::{admission::Cidr, error::Error, protocol, users};
+
+/// How long `/mute` lasts when no duration is given.
+pub const DEFAULT_MUTE: Duration = Duration::from_secs(300);
+
+/// Who may moderate, and where bans are kept.
+///
+/// Clients become operators by logging in to one of the `operators`
+/// accounts, or by sending `/oper` with the `password`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModerationConfig {
+    /// Account names that are operators as soon as they log in. Requires
+    /// `auth.accounts`.
+    pub operators: Vec<String>,
+    /// Password for `/oper`. `None` disables `/oper`.
+    pub password: Option<String>,
+    /// File the ban list is kept in, created if missing. Without one, bans
+    /// last until the server stops.
+    pub bans: Option<PathBuf>,
+}
+
+/// Who a ban applies to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum BanTarget {
+    /// A nickname or account name, ignoring ASCII case.
+    Nick(String),
+    /// Every address in a network.
+    Net(Cidr),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("`{0}` is neither a nickname nor an IP address or network")]
+pub struct BanTargetError(String);
+
+impl BanTarget {
+    /// Whether both name the same nickname or network.
+    fn same(&self, other: &BanTarget) -> bool {
+        match (self, other) {
+            (BanTarget::Nick(a), BanTarget::Nick(b)) => a.eq_ignore_ascii_case(b),
+            (a, b) => a == b,
+        }
+    }
+
+    fn matches_nick(&self, nick: &str) -> bool {
+        matches!(self, BanTarget::Nick(banned) if banned.eq_ignore_ascii_case(nick))
+    }
+
+    fn matches_ip(&self, ip: IpAddr) -> bool {
+        matches!(self, BanTarget::Net(net) if net.contains(ip))
+    }
+
+    /// Whether the client called `nick` connecting from `ip` is covered.
+    pub fn matches(&self, nick: &str, ip: IpAddr) -> bool {
+        self.matches_nick(nick) || self.matches_ip(ip)
+    }
+}
+
+impl FromStr for BanTarget {
+    type Err = BanTargetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(net) = s.parse() {
+            return Ok(BanTarget::Net(net));
+        }
+        users::validate(s)
+            .map(|()| BanTarget::Nick(s.to_string()))
+            .map_err(|_| BanTargetError(s.to_string()))
+    }
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanTarget::Nick(nick) => f.write_str(nick),
+            BanTarget::Net(net) => net.fmt(f),
+        }
+    }
+}
+
+impl TryFrom<String> for BanTarget {
+    type Error = BanTargetError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<BanTarget> for String {
+    fn from(target: BanTarget) -> Self {
+        target.to_string()
+    }
+}
+
+/// One entry in the ban list, stored as a line of JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ban {
+    pub target: BanTarget,
+    /// The operator who set it.
+    pub by: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Milliseconds since the Unix epoch. `None` for a permanent ban.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+impl Ban {
+    fn expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// Something an operator did to a connected client, delivered to its
+/// connection task.
+#[derive(Debug, Clone)]
+pub(crate) enum Sanction {
+    /// Disconnect the client. The text completes "you were ...", as in
+    /// `kicked by alice: spam`.
+    Remove(String),
+    Mute {
+        duration: Duration,
+        notice: String,
+    },
+}
+
+/// The operators and the ban list.
+#[derive(Debug)]
+pub(crate) struct Moderation {
+    /// Lowercase operator account names and the `/oper` password.
+    operators: RwLock<(HashSet<String>, Option<String>)>,
+    path: Option<PathBuf>,
+    bans: Mutex<Vec<Ban>>,
+    /// Held while the ban file is written, so that saves land in order.
+    saving: AsyncMutex<()>,
+}
+
+impl Moderation {
+    /// Loads the ban list, if there is one.
+    pub fn open(config: &ModerationConfig) -> Result<Self, Error> {
+        let bans = match &config.bans {
+            Some(path) => load(path).map_err(|source| Error::Bans {
+                path: path.clone(),
+                source,
+            })?,
+            None => Vec::new(),
+        };
+        Ok(Moderation {
+            operators: RwLock::new(operators(config)),
+            path: config.bans.clone(),
+            bans: Mutex::new(bans),
+            saving: AsyncMutex::new(()),
+        })
+    }
+
+    /// Replaces the operators and `/oper` password. The ban file stays
+    /// where it is.
+    pub fn reconfigure(&self, config: &ModerationConfig) {
+        *self.operators.write().unwrap() = operators(config);
+    }
+
+    /// Whether the account `name` is configured as an operator.
+    pub fn is_operator(&self, name: &str) -> bool {
+        let operators = self.operators.read().unwrap();
+        operators.0.contains(&name.to_ascii_lowercase())
+    }
+
+    /// Checks an `/oper` password. `None` if `/oper` is disabled.
+    pub fn check_password(&self, password: &str) -> Option<bool> {
+        let operators = self.operators.read().unwrap();
+        let expected = operators.1.as_deref()?;
+        // Compares every byte so the time taken says nothing about where the
+        // first difference is.
+        let same = expected.len() == password.len()
+            && expected
+                .bytes()
+                .zip(password.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0;
+        Some(same)
+    }
+
+    /// The ban covering connections from `ip`, if any.
+    pub fn banned_ip(&self, ip: IpAddr) -> Option<Ban> {
+        self.find(|ban| ban.target.matches_ip(ip))
+    }
+
+    /// The ban covering the nickname `nick`, if any.
+    pub fn banned_nick(&self, nick: &str) -> Option<Ban> {
+        self.find(|ban| ban.target.matches_nick(nick))
+    }
+
+    fn find(&self, matches: impl Fn(&Ban) -> bool) -> Option<Ban> {
+        let now = protocol::timestamp();
+        let bans = self.bans.lock().unwrap();
+        bans.iter()
+            .find(|ban| !ban.expired(now) && matches(ban))
+            .cloned()
+    }
+
+    /// Adds `ban`, replacing any earlier ban of the same target, and saves
+    /// the list.
+    pub async fn ban(&self, ban: Ban) -> Result<(), Error> {
+        let _saving = self.saving.lock().await;
+        let bans = {
+            let mut bans = self.bans.lock().unwrap();
+            let now = protocol::timestamp();
+            bans.retain(|old| !old.target.same(&ban.target) && !old.expired(now));
+            bans.push(ban);
+            bans.clone()
+        };
+        self.save(bans).await
+    }
+
+    /// Lifts the ban of `target`. Returns whether there was one.
+    pub async fn unban(&self, target: &BanTarget) -> Result<bool, Error> {
+        let _saving = self.saving.lock().await;
+        let bans = {
+            let mut bans = self.bans.lock().unwrap();
+            let before = bans.len();
+            bans.retain(|ban| !ban.target.same(target));
+            if bans.len() == before {
+                return Ok(false);
+            }
+            bans.clone()
+        };
+        self.save(bans).await?;
+        Ok(true)
+    }
+
+    /// The bans still in force.
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = protocol::timestamp();
+        let bans = self.bans.lock().unwrap();
+        bans.iter()
+            .filter(|ban| !ban.expired(now))
+            .cloned()
+            .collect()
+    }
+
+    /// Rewrites the ban file, replacing it only once the new one is
+    /// complete. The writing happens off the runtime and without the ban
+    /// list locked, so that connections are still admitted meanwhile.
+    async fn save(&self, bans: Vec<Ban>) -> Result<(), Error> {
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+        let write = {
+            let path = path.clone();
+            move || {
+                let temp = path.with_extension("tmp");
+                let mut file = fs::File::create(&temp)?;
+                for ban in &bans {
+                    serde_json::to_writer(&mut file, ban)?;
+                    file.write_all(b"\n")?;
+                }
+                file.sync_data()?;
+                fs::rename(&temp, &path)
+            }
+        };
+        task::spawn_blocking(write)
+            .await
+            .expect("saving the ban list panicked")
+            .map_err(|source: io::Error| Error::Bans { path, source })
+    }
+}
+
+fn operators(config: &ModerationConfig) -> (HashSet<String>, Option<String>) {
+    let names = config
+        .operators
+        .iter()
+        .map(|name| name.to_ascii_lowercase())
+        .collect();
+    (names, config.password.clone())
+}
+
+fn load(path: &Path) -> io::Result<Vec<Ban>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+    let now = protocol::timestamp();
+    let mut bans = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let ban: Ban = serde_json::from_str(line).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {err}", number + 1),
+            )
+        })?;
+        if !ban.expired(now) {
+            bans.push(ban);
+        }
+    }
+    Ok(bans)
+}
+
+/// Parses a duration such as `90s`, `10m`, `2h` or `7d`. A bare number is
+/// seconds. Zero is not a duration.
+pub(crate) fn parse_duration(s: &str) -> Option<Duration> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(at) => s.split_at(at),
+        None => (s, "s"),
+    };
+    let number: u64 = number.parse().ok()?;
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    number
+        .checked_mul(unit)
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_take_a_unit() {
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("10m"), Some(Duration::from_secs(600)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("7d"), Some(Duration::from_secs(604_800)));
+        assert_eq!(parse_duration("45"), Some(Duration::from_secs(45)));
+    }
+
+    #[test]
+    fn nonsense_is_not_a_duration() {
+        for s in ["", "0", "0m", "m", "10w", "1h30m", "-5s", " 5s", "5 s"] {
+            assert_eq!(parse_duration(s), None, "{s:?}");
+        }
+        assert_eq!(parse_duration(&format!("{}d", u64::MAX / 2)), None);
+    }
+
+    #[tokio::test]
+    async fn bans_are_saved_in_order() {
+        let path = std::env::temp_dir().join(format!("msg_server-bans-{}", std::process::id()));
+        let config = ModerationConfig {
+            bans: Some(path.clone()),
+            ..ModerationConfig::default()
+        };
+        let moderation = Moderation::open(&config).unwrap();
+        let ban = |target: &str| Ban {
+            target: target.parse().unwrap(),
+            by: "op".to_string(),
+            reason: None,
+            expires: None,
+        };
+        let (first, second) = tokio::join!(
+            moderation.ban(ban("mallory")),
+            moderation.ban(ban("10.0.0.0/8"))
+        );
+        first.unwrap();
+        second.unwrap();
+        assert!(moderation.unban(&"mallory".parse().unwrap()).await.unwrap());
+
+        let reopened = Moderation::open(&config).unwrap();
+        assert_eq!(reopened.bans(), vec![ban("10.0.0.0/8")]);
+        assert!(reopened.banned_ip("10.1.2.3".parse().unwrap()).is_some());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,370 @@
+//! The wire protocol shared by the server and its clients.
+//!
+//! Clients either speak plain newline-delimited text (so `nc` keeps
+//! working) or the framed protocol. A framed client opens the connection by
+//! sending [`preamble`]; after that every message in both directions is a
+//! big-endian `u32` length followed by that many bytes of JSON encoding a
+//! [`Message`].
+
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Marks a framed connection. The leading NUL never starts a text line.
+pub const MAGIC: [u8; 4] = *b"\0MSG";
+pub const VERSION: u8 = 1;
+pub const DEFAULT_MAX_FRAME_LEN: usize = 64 * 1024;
+
+/// Bytes in a frame's length prefix.
+pub const LEN_PREFIX: usize = 4;
+
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("not a framed connection")]
+    BadMagic,
+    #[error("unsupported protocol version {0}, expected {VERSION}")]
+    UnsupportedVersion(u8),
+    #[error("frame of {len} bytes exceeds the {max} byte limit")]
+    FrameTooLarge { len: usize, max: usize },
+    #[error("invalid message: {0}")]
+    InvalidMessage(#[from] serde_json::Error),
+}
+
+/// Everything that can be sent over the framed protocol, in either
+/// direction.
+///
+/// Optional fields are filled in by the server: a client sends
+/// `Chat { text, .. }` and its peers receive it with `id`, `from` and `ts`
+/// set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    /// Sent by the server once a framed connection is accepted.
+    Hello {
+        version: u8,
+    },
+    /// A chat line. Goes to `to` if set, otherwise to `room`, otherwise to
+    /// the sender's current room. Client text starting with `/` and no
+    /// `room` or `to` is run as a command.
+    ///
+    /// From a client, `id` is echoed back in an [`Message::Ack`] and in any
+    /// [`Message::Error`] it causes; from the server it is the message's
+    /// sequence number in its room.
+    Chat {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        to: Option<String>,
+        text: String,
+        /// Milliseconds since the Unix epoch.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ts: Option<u64>,
+    },
+    /// A request to join `room`, or the announcement that `nick` joined it.
+    /// Without `nick`, the receiving client itself joined.
+    ///
+    /// A joining client is sent the room's recent messages, or with `since`
+    /// every retained message after that point, before live traffic.
+    Join {
+        room: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nick: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        since: Option<Since>,
+    },
+    /// A request to leave `room`, or the announcement that `nick` left it.
+    Leave {
+        room: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nick: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// Announces that `nick` connected. The counterpart of
+    /// [`Message::Quit`].
+    Online {
+        nick: String,
+    },
+    /// A request to disconnect, or the announcement that `nick` did.
+    Quit {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nick: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// A request to use `nick`, or the announcement that `old` is now
+    /// `nick`. Without `old`, the receiving client itself was renamed.
+    Nick {
+        nick: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        old: Option<String>,
+    },
+    /// Logs in to an existing account during the handshake, when the
+    /// server requires accounts.
+    Login {
+        user: String,
+        password: String,
+    },
+    /// Creates an account and logs in to it.
+    Register {
+        user: String,
+        password: String,
+    },
+    System {
+        text: String,
+    },
+    /// A problem, with the `id` of the client message it answers, if any.
+    /// Errors without an `id` may arrive at any time.
+    Error {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        text: String,
+    },
+    /// Checks the other side is still there. Answered with a `Pong`
+    /// carrying the same token.
+    Ping {
+        token: u64,
+    },
+    Pong {
+        token: u64,
+    },
+    /// Confirms the client message carrying `id` was processed.
+    Ack {
+        id: u64,
+    },
+}
+
+/// A point in a room's history: after the chat message with id `Id`, or
+/// after `Ts` milliseconds since the Unix epoch. Serialized as `{"id": 42}`
+/// or `{"ts": 1700000000000}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Since {
+    Id(u64),
+    Ts(u64),
+}
+
+impl FromStr for Since {
+    type Err = ParseSinceError;
+
+    /// Parses `42` as an id and `@1700000000000` as a timestamp.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix('@') {
+            Some(ts) => ts.parse().map(Since::Ts),
+            None => s.parse().map(Since::Id),
+        }
+        .map_err(|_| ParseSinceError)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("expected a message id or `@` and a timestamp in milliseconds")]
+pub struct ParseSinceError;
+
+impl Message {
+    /// A chat line with nothing but text, as a client sends it.
+    pub fn chat(text: impl Into<String>) -> Self {
+        Message::Chat {
+            id: None,
+            room: None,
+            from: None,
+            to: None,
+            text: text.into(),
+            ts: None,
+        }
+    }
+
+    pub fn system(text: impl Into<String>) -> Self {
+        Message::System { text: text.into() }
+    }
+
+    pub fn error(text: impl Into<String>) -> Self {
+        Message::Error {
+            id: None,
+            text: text.into(),
+        }
+    }
+
+    /// Formats the message as a line for plain-text clients, without the
+    /// trailing newline. Returns `None` for messages text clients don't see.
+    pub fn to_text(&self) -> Option<String> {
+        let line = match self {
+            Message::Hello { .. }
+            | Message::Ack { .. }
+            | Message::Ping { .. }
+            | Message::Pong { .. }
+            | Message::Login { .. }
+            | Message::Register { .. } => return None,
+            Message::Chat {
+                room,
+                from,
+                to,
+                text,
+                ..
+            } => match (from, to, room) {
+                (Some(from), Some(_), _) => format!("[private] <{from}> {text}"),
+                (Some(from), None, Some(room)) => format!("[{room}] <{from}> {text}"),
+                (Some(from), None, None) => format!("<{from}> {text}"),
+                (None, _, _) => text.clone(),
+            },
+            Message::Join {
+                room, nick: None, ..
+            } => format!("*** joined {room}"),
+            Message::Join {
+                room,
+                nick: Some(nick),
+                ..
+            } => format!("*** {nick} has joined {room}"),
+            Message::Leave {
+                room, nick, reason, ..
+            } => {
+                let mut line = match nick {
+                    Some(nick) => format!("*** {nick} has left {room}"),
+                    None => format!("*** left {room}"),
+                };
+                if let Some(reason) = reason {
+                    line.push_str(&format!(" ({reason})"));
+                }
+                line
+            }
+            Message::Online { nick } => format!("*** {nick} has connected"),
+            Message::Quit { nick: None, .. } => return None,
+            Message::Quit {
+                nick: Some(nick),
+                reason,
+            } => match reason {
+                Some(reason) => format!("*** {nick} has quit ({reason})"),
+                None => format!("*** {nick} has quit"),
+            },
+            Message::Nick { nick, old: None } => format!("*** you are now known as {nick}"),
+            Message::Nick {
+                nick,
+                old: Some(old),
+            } => format!("*** {old} is now known as {nick}"),
+            Message::System { text } => format!("*** {text}"),
+            Message::Error { text, .. } => format!("*** error: {text}"),
+        };
+        Some(line)
+    }
+}
+
+/// The bytes a framed client sends before its first message.
+pub fn preamble() -> [u8; 5] {
+    let mut bytes = [0; 5];
+    bytes[..4].copy_from_slice(&MAGIC);
+    bytes[4] = VERSION;
+    bytes
+}
+
+/// Checks a preamble read from a client.
+pub fn check_preamble(bytes: &[u8; 5]) -> Result<(), ProtocolError> {
+    if bytes[..4] != MAGIC {
+        return Err(ProtocolError::BadMagic);
+    }
+    if bytes[4] != VERSION {
+        return Err(ProtocolError::UnsupportedVersion(bytes[4]));
+    }
+    Ok(())
+}
+
+/// Encodes `msg` as a length-prefixed frame.
+pub fn encode(msg: &Message) -> Vec<u8> {
+    let body = serde_json::to_vec(msg).expect("messages always serialize");
+    let mut frame = Vec::with_capacity(LEN_PREFIX + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    frame
+}
+
+/// Decodes the first frame in `buf`. Returns the message and the number of
+/// bytes it used, or `None` if `buf` doesn't hold a whole frame yet.
+pub fn decode(buf: &[u8], max_len: usize) -> Result<Option<(Message, usize)>, ProtocolError> {
+    let Some(prefix) = buf.get(..LEN_PREFIX) else {
+        return Ok(None);
+    };
+    let len = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+    if len > max_len {
+        return Err(ProtocolError::FrameTooLarge { len, max: max_len });
+    }
+    let Some(body) = buf.get(LEN_PREFIX..LEN_PREFIX + len) else {
+        return Ok(None);
+    };
+    let msg = serde_json::from_slice(body)?;
+    Ok(Some((msg, LEN_PREFIX + len)))
+}
+
+/// Milliseconds since the Unix epoch, as used in [`Message::Chat`].
+pub fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_decode_once_complete() {
+        let msg = Message::chat("hello");
+        let mut buf = encode(&msg);
+        let len = buf.len();
+        for end in 0..len {
+            assert!(decode(&buf[..end], 1024).unwrap().is_none(), "{end} bytes");
+        }
+        buf.extend_from_slice(&encode(&Message::Ping { token: 1 }));
+        assert_eq!(decode(&buf, 1024).unwrap(), Some((msg, len)));
+    }
+
+    #[test]
+    fn long_frames_are_refused_from_the_prefix() {
+        let prefix = 2000u32.to_be_bytes();
+        assert!(matches!(
+            decode(&prefix, 1024),
+            Err(ProtocolError::FrameTooLarge {
+                len: 2000,
+                max: 1024
+            })
+        ));
+    }
+
+    #[test]
+    fn bad_json_is_an_invalid_message() {
+        let mut buf = 8u32.to_be_bytes().to_vec();
+        buf.extend_from_slice(br#"{"type":"#);
+        assert!(matches!(
+            decode(&buf, 1024),
+            Err(ProtocolError::InvalidMessage(_))
+        ));
+        let mut buf = 19u32.to_be_bytes().to_vec();
+        buf.extend_from_slice(br#"{"type":"shrug"}   "#);
+        assert!(matches!(
+            decode(&buf, 1024),
+            Err(ProtocolError::InvalidMessage(_))
+        ));
+    }
+
+    #[test]
+    fn preambles_carry_the_magic_and_version() {
+        assert!(check_preamble(&preamble()).is_ok());
+
+        let mut other = preamble();
+        other[4] = VERSION + 1;
+        assert!(matches!(
+            check_preamble(&other),
+            Err(ProtocolError::UnsupportedVersion(v)) if v == VERSION + 1
+        ));
+        assert!(matches!(
+            check_preamble(b"hello"),
+            Err(ProtocolError::BadMagic)
+        ));
+    }
+}
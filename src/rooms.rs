@@ -0,0 +1,142 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use thiserror::Error;
+
+use crate::{
+    channel::{Channel, Subscription},
+    error::Error,
+    history::Store,
+    protocol::{Message, Since},
+};
+
+pub const MAX_ROOM_NAME_LEN: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum RoomError {
+    #[error(
+        "room names must be `#` followed by 1-{MAX_ROOM_NAME_LEN} letters, digits, `_` or `-`"
+    )]
+    InvalidName,
+}
+
+/// Named chat rooms. Each room has its own broadcast channel, created when
+/// the first member joins and dropped when the last one leaves. Room
+/// history outlives the channel in the [`Store`].
+#[derive(Debug)]
+pub(crate) struct Rooms {
+    capacity: usize,
+    history: usize,
+    store: Arc<Store>,
+    rooms: Mutex<BTreeMap<String, Room>>,
+}
+
+#[derive(Debug)]
+struct Room {
+    channel: Arc<Channel>,
+    members: HashSet<SocketAddr>,
+}
+
+impl Rooms {
+    pub fn new(capacity: usize, history: usize, store: Arc<Store>) -> Self {
+        Rooms {
+            capacity,
+            history,
+            store,
+            rooms: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Adds `addr` to `room`, creating the room if needed, and subscribes to
+    /// it. `room` must already be normalized.
+    ///
+    /// Also returns the history to replay to the new member: the messages
+    /// after `since`, or the store's usual number of recent ones.
+    pub async fn join(
+        &self,
+        room: &str,
+        addr: SocketAddr,
+        since: Option<Since>,
+    ) -> Result<(Subscription, Vec<Message>), Error> {
+        // Loaded first, since it may take reading a file.
+        let log = self.store.log(room).await?;
+        let mut rooms = self.rooms.lock().unwrap();
+        let entry = rooms.entry(room.to_string()).or_insert_with(|| Room {
+            channel: Channel::with_log(self.capacity, self.history, Some(log)),
+            members: HashSet::new(),
+        });
+        entry.members.insert(addr);
+        // Subscribed under the lock so the room can't be dropped in between.
+        Ok(entry
+            .channel
+            .subscribe_replaying(since, self.store.replay()))
+    }
+
+    /// Removes `addr` from `room`, dropping the room if it is now empty.
+    /// Returns whether `addr` was a member.
+    pub fn part(&self, room: &str, addr: SocketAddr) -> bool {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(entry) = rooms.get_mut(room) else {
+            return false;
+        };
+        let was_member = entry.members.remove(&addr);
+        if entry.members.is_empty() {
+            rooms.remove(room);
+        }
+        was_member
+    }
+
+    /// Removes `addr` from every room it is in.
+    pub fn part_all(&self, addr: SocketAddr) {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.retain(|_, room| {
+            room.members.remove(&addr);
+            !room.members.is_empty()
+        });
+    }
+
+    /// Room names and member counts, sorted by name.
+    pub fn list(&self) -> Vec<(String, usize)> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms
+            .iter()
+            .map(|(name, room)| (name.clone(), room.members.len()))
+            .collect()
+    }
+
+    /// The rooms `addr` is a member of, sorted by name.
+    pub fn rooms_of(&self, addr: SocketAddr) -> Vec<String> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms
+            .iter()
+            .filter(|(_, room)| room.members.contains(&addr))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub fn members(&self, room: &str) -> Option<Vec<SocketAddr>> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms
+            .get(room)
+            .map(|room| room.members.iter().copied().collect())
+    }
+}
+
+/// Validates a room name and puts it in canonical form: lowercase with a
+/// leading `#`, which may be omitted in `name`.
+pub fn normalize(name: &str) -> Result<String, RoomError> {
+    let bare = name.strip_prefix('#').unwrap_or(name);
+    let valid = !bare.is_empty()
+        && bare.len() <= MAX_ROOM_NAME_LEN
+        && bare
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if valid {
+        Ok(format!("#{}", bare.to_ascii_lowercase()))
+    } else {
+        Err(RoomError::InvalidName)
+    }
+}
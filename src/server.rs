@@ -0,0 +1,963 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    net::TcpListener,
+    sync::{watch, Notify},
+    task::JoinSet,
+    time,
+};
+use tracing::{field, Instrument};
+
+use crate::{
+    accounts::{Accounts, AuthConfig},
+    admin::{self, ClientInfo, RoomInfo},
+    admission::{Admission, AdmissionConfig, Refusal},
+    channel::Channel,
+    commands::{Commands, Custom, Invocation},
+    config::{Config, ConfigError, DEFAULT_ROOM},
+    connection,
+    error::{Error, Result},
+    heartbeat::Heartbeat,
+    history::{HistoryConfig, Store},
+    hooks::Hooks,
+    lag::LagPolicy,
+    limit::{Limiter, RateLimit},
+    metrics::{self, Metrics},
+    moderation::{Moderation, ModerationConfig, Sanction},
+    protocol::{Message, DEFAULT_MAX_FRAME_LEN},
+    rooms::{self, Rooms},
+    tls::rustls,
+    users::{Presence, Users},
+};
+
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 10;
+/// How long a shutting down server waits for clients to be sent what is
+/// queued for them.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The kind of connections a listener accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Endpoint {
+    /// Plain text or framed messages, detected per connection.
+    Tcp,
+    /// [`Endpoint::Tcp`] inside TLS.
+    Tls,
+    WebSocket,
+    /// WebSocket over TLS (`wss://`).
+    SecureWebSocket,
+}
+
+impl Endpoint {
+    pub fn is_tls(self) -> bool {
+        matches!(self, Endpoint::Tls | Endpoint::SecureWebSocket)
+    }
+
+    pub fn is_websocket(self) -> bool {
+        matches!(self, Endpoint::WebSocket | Endpoint::SecureWebSocket)
+    }
+}
+
+/// The sender of messages the server itself broadcasts.
+const SERVER_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+/// State shared between the accept loops, connection tasks and handles.
+#[derive(Debug)]
+pub(crate) struct Shared {
+    pub channel: Arc<Channel>,
+    pub lag: LagPolicy,
+    pub heartbeat: Heartbeat,
+    pub admission: Admission,
+    /// Longest line, frame or WebSocket message accepted from a client.
+    pub max_message_len: usize,
+    pub limiter: Limiter,
+    pub hooks: Hooks,
+    pub commands: Commands,
+    pub users: Users,
+    pub rooms: Rooms,
+    pub history: Arc<Store>,
+    /// Set when clients must log in.
+    pub accounts: Option<Accounts>,
+    pub moderation: Moderation,
+    pub default_room: Option<String>,
+    /// Every open connection.
+    pub peers: Mutex<HashMap<SocketAddr, Peer>>,
+    /// Notified when the last peer disconnects.
+    pub idle: Notify,
+    pub shutdown: watch::Sender<bool>,
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+    pub metrics: Metrics,
+    endpoints: Vec<(SocketAddr, Endpoint)>,
+    metrics_addrs: Vec<SocketAddr>,
+}
+
+/// An open connection.
+#[derive(Debug)]
+pub(crate) struct Peer {
+    pub endpoint: Endpoint,
+    pub connected_at: SystemTime,
+    pub stats: Arc<PeerStats>,
+}
+
+/// Counters kept by a connection's task.
+#[derive(Debug, Default)]
+pub(crate) struct PeerStats {
+    /// Messages and commands the client has sent.
+    pub messages: AtomicU64,
+    /// Times the client fell behind.
+    pub lags: AtomicU64,
+}
+
+impl Shared {
+    /// The registered clients, sorted by nickname.
+    pub fn online(&self) -> Vec<Presence> {
+        let peers = self.peers.lock().unwrap();
+        let mut online: Vec<_> = peers
+            .iter()
+            .filter_map(|(&addr, peer)| {
+                Some(Presence {
+                    nick: self.users.nick(addr)?,
+                    addr,
+                    connected_at: peer.connected_at,
+                })
+            })
+            .collect();
+        online.sort_unstable_by(|a, b| a.nick.cmp(&b.nick));
+        online
+    }
+
+    /// Waits until no clients are connected.
+    async fn wait_idle(&self) {
+        loop {
+            let idle = self.idle.notified();
+            if self.peers.lock().unwrap().is_empty() {
+                return;
+            }
+            idle.await;
+        }
+    }
+
+    fn addrs_of(&self, wanted: Endpoint) -> Vec<SocketAddr> {
+        self.endpoints
+            .iter()
+            .filter(|(_, endpoint)| *endpoint == wanted)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+}
+
+/// Configures and binds a [`Server`].
+#[derive(Debug)]
+pub struct ServerBuilder {
+    addrs: Vec<(SocketAddr, Endpoint)>,
+    listeners: Vec<(TcpListener, Endpoint)>,
+    channel_capacity: usize,
+    lag: LagPolicy,
+    heartbeat: Heartbeat,
+    admission: AdmissionConfig,
+    max_message_len: usize,
+    rate_limit: RateLimit,
+    history: HistoryConfig,
+    auth: AuthConfig,
+    moderation: ModerationConfig,
+    default_room: Option<String>,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    shutdown_timeout: Duration,
+    admin_socket: Option<PathBuf>,
+    metrics_addrs: Vec<SocketAddr>,
+    hooks: Hooks,
+    commands: Commands,
+}
+
+/// A bound broadcast server. Call [`Server::run`] to start serving.
+#[derive(Debug)]
+pub struct Server {
+    listeners: Vec<(TcpListener, Endpoint)>,
+    metrics_listeners: Vec<TcpListener>,
+    #[cfg(unix)]
+    admin: Option<(UnixListener, PathBuf)>,
+    shutdown_timeout: Duration,
+    shared: Arc<Shared>,
+}
+
+/// A cheap, cloneable handle for inspecting and stopping a running server.
+#[derive(Debug, Clone)]
+pub struct ServerHandle {
+    shared: Arc<Shared>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder {
+            addrs: Vec::new(),
+            listeners: Vec::new(),
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            lag: LagPolicy::default(),
+            heartbeat: Heartbeat::default(),
+            admission: AdmissionConfig::default(),
+            max_message_len: DEFAULT_MAX_FRAME_LEN,
+            rate_limit: RateLimit::default(),
+            history: HistoryConfig::default(),
+            auth: AuthConfig::default(),
+            moderation: ModerationConfig::default(),
+            default_room: Some(DEFAULT_ROOM.to_string()),
+            tls: None,
+            admin_socket: None,
+            metrics_addrs: Vec::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            hooks: Hooks::default(),
+            commands: Commands::default(),
+        }
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a builder listening on every address in `config`.
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        config.validate()?;
+        let mut builder = Self::new();
+        for addr in config.listen_addrs()? {
+            builder = builder.bind(addr);
+        }
+        let websocket = if config.websocket.tls {
+            Endpoint::SecureWebSocket
+        } else {
+            Endpoint::WebSocket
+        };
+        for addr in config.websocket_addrs()? {
+            builder = builder.bind_endpoint(addr, websocket);
+        }
+        for addr in config.tls_addrs()? {
+            builder = builder.bind_endpoint(addr, Endpoint::Tls);
+        }
+        if let Some(tls) = config.tls.load()? {
+            builder = builder.tls(tls);
+        }
+        builder.channel_capacity = config.channel_capacity;
+        builder.lag = config.lag.clone();
+        builder.heartbeat = config.heartbeat.clone();
+        builder.admission = config.admission.clone();
+        builder.max_message_len = config.max_message_len;
+        builder.rate_limit = config.rate_limit.clone();
+        builder.history = config.history.clone();
+        builder.auth = config.auth.clone();
+        builder.moderation = config.moderation.clone();
+        builder.default_room = config.default_room()?;
+        builder.shutdown_timeout = Duration::from_secs(config.shutdown_timeout_secs);
+        builder.admin_socket = config.admin.socket.clone();
+        builder.metrics_addrs = config.metrics_addrs()?;
+        Ok(builder)
+    }
+
+    /// Adds an address to bind when the server is built.
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.addrs.push((addr, Endpoint::Tcp));
+        self
+    }
+
+    /// Adds an already bound listener.
+    pub fn listener(mut self, listener: TcpListener) -> Self {
+        self.listeners.push((listener, Endpoint::Tcp));
+        self
+    }
+
+    /// Adds an address to accept WebSocket connections on.
+    pub fn bind_websocket(mut self, addr: SocketAddr) -> Self {
+        self.addrs.push((addr, Endpoint::WebSocket));
+        self
+    }
+
+    /// Adds an already bound listener for WebSocket connections.
+    pub fn websocket_listener(mut self, listener: TcpListener) -> Self {
+        self.listeners.push((listener, Endpoint::WebSocket));
+        self
+    }
+
+    /// Adds an address to accept `endpoint` connections on.
+    pub fn bind_endpoint(mut self, addr: SocketAddr, endpoint: Endpoint) -> Self {
+        self.addrs.push((addr, endpoint));
+        self
+    }
+
+    /// Adds an already bound listener for `endpoint` connections.
+    pub fn endpoint_listener(mut self, listener: TcpListener, endpoint: Endpoint) -> Self {
+        self.listeners.push((listener, endpoint));
+        self
+    }
+
+    /// Sets the certificate and client authentication used by TLS
+    /// endpoints. See [`tls::load_server_config`](crate::tls::load_server_config).
+    pub fn tls(mut self, config: Arc<rustls::ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Sets how many messages the broadcast channel buffers per receiver.
+    /// Must be greater than zero.
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        self.channel_capacity = capacity;
+        self
+    }
+
+    /// Sets how lagging clients are notified, resynced and disconnected.
+    pub fn lag_policy(mut self, policy: LagPolicy) -> Self {
+        self.lag = policy;
+        self
+    }
+
+    /// Sets how often quiet clients are pinged, when silent ones are
+    /// dropped and whether TCP keepalive is used. [`build`](Self::build)
+    /// fails if any of its intervals is zero.
+    pub fn heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// Sets connection limits and which addresses may connect.
+    pub fn admission(mut self, config: AdmissionConfig) -> Self {
+        self.admission = config;
+        self
+    }
+
+    /// Sets the longest line, frame or WebSocket message accepted from a
+    /// client, in bytes. Longer ones are dropped with an error and repeat
+    /// offenders disconnected.
+    pub fn max_message_len(mut self, len: usize) -> Self {
+        self.max_message_len = len;
+        self
+    }
+
+    /// Sets how fast clients may send and how floods are punished.
+    pub fn rate_limit(mut self, policy: RateLimit) -> Self {
+        self.rate_limit = policy;
+        self
+    }
+
+    /// Sets where room history is kept and how much of it joining clients
+    /// are sent.
+    pub fn history(mut self, config: HistoryConfig) -> Self {
+        self.history = config;
+        self
+    }
+
+    /// Sets the account store clients must log in to, if any.
+    pub fn auth(mut self, config: AuthConfig) -> Self {
+        self.auth = config;
+        self
+    }
+
+    /// Sets who may moderate and where bans are kept. Operator accounts
+    /// only count when [`auth`](Self::auth) enables accounts.
+    pub fn moderation(mut self, config: ModerationConfig) -> Self {
+        self.moderation = config;
+        self
+    }
+
+    /// Sets the room clients join after registering, or `None` for no room.
+    pub fn default_room(mut self, room: Option<&str>) -> Self {
+        self.default_room = room.map(str::to_string);
+        self
+    }
+
+    /// Sets how long [`Server::run`] waits for clients to be sent their
+    /// queued messages and the shutdown notice before giving up on them.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Serves Prometheus metrics at `http://addr/metrics`. May be called
+    /// more than once.
+    pub fn metrics(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addrs.push(addr);
+        self
+    }
+
+    /// Serves the admin API on a Unix socket at `path`. See [`admin`](crate::admin).
+    pub fn admin_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.admin_socket = Some(path.into());
+        self
+    }
+
+    /// Sets how [`ServerHandle::reload`] gets the new config, usually by
+    /// reading the config file again.
+    pub fn reload_with(
+        mut self,
+        loader: impl Fn() -> std::result::Result<Config, ConfigError> + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.reload = Some(Arc::new(loader));
+        self
+    }
+
+    /// Called when a client connects.
+    pub fn on_connect(mut self, hook: impl Fn(SocketAddr) + Send + Sync + 'static) -> Self {
+        self.hooks.on_connect = Some(Arc::new(hook));
+        self
+    }
+
+    /// Called with every line a client sends, before it is broadcast.
+    pub fn on_message(mut self, hook: impl Fn(SocketAddr, &str) + Send + Sync + 'static) -> Self {
+        self.hooks.on_message = Some(Arc::new(hook));
+        self
+    }
+
+    /// Called when a client's connection closes.
+    pub fn on_disconnect(mut self, hook: impl Fn(SocketAddr) + Send + Sync + 'static) -> Self {
+        self.hooks.on_disconnect = Some(Arc::new(hook));
+        self
+    }
+
+    /// Called with errors the server recovered from, such as a failed
+    /// `accept` or a connection closed because of a bad client.
+    pub fn on_error(mut self, hook: impl Fn(&Error) + Send + Sync + 'static) -> Self {
+        self.hooks.on_error = Some(Arc::new(hook));
+        self
+    }
+
+    /// Adds the slash command `/name`, described by `help` in `/help`.
+    /// Whatever the handler returns is sent only to the client that ran it.
+    ///
+    /// Built-in command names can't be reused; [`build`](Self::build)
+    /// fails if one is.
+    ///
+    /// ```no_run
+    /// # fn example() -> msg_server::Result<()> {
+    /// let server = msg_server::Server::builder()
+    ///     .bind("127.0.0.1:8080".parse().unwrap())
+    ///     .command("count", "say how many clients are connected", |cmd| {
+    ///         Ok(format!("{} connected", cmd.server.connection_count()))
+    ///     })
+    ///     .build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn command(
+        mut self,
+        name: impl Into<String>,
+        help: impl Into<String>,
+        handler: impl Fn(&Invocation<'_>) -> std::result::Result<String, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.commands.insert(
+            name.into(),
+            Custom {
+                help: help.into(),
+                handler: Arc::new(handler),
+            },
+        );
+        self
+    }
+
+    /// Binds every configured address and returns the server, ready to run.
+    pub fn build(self) -> Result<Server> {
+        if let Some(name) = self.commands.invalid_name() {
+            return Err(Error::InvalidCommand(name.to_string()));
+        }
+        if self.channel_capacity == 0 {
+            return Err(ConfigError::ZeroChannelCapacity.into());
+        }
+        if self.max_message_len == 0 {
+            return Err(ConfigError::ZeroMaxMessageLen.into());
+        }
+        self.lag.validate()?;
+        self.heartbeat.validate()?;
+        self.history.validate()?;
+        self.admission.validate()?;
+        self.rate_limit.validate()?;
+        let mut listeners = self.listeners;
+        for (addr, endpoint) in self.addrs {
+            let listener = bind(addr).map_err(|source| Error::Bind { addr, source })?;
+            listeners.push((listener, endpoint));
+        }
+        if listeners.is_empty() {
+            return Err(Error::NoListeners);
+        }
+        if self.tls.is_none() && listeners.iter().any(|(_, endpoint)| endpoint.is_tls()) {
+            return Err(Error::TlsNotConfigured);
+        }
+        let default_room = self
+            .default_room
+            .as_deref()
+            .map(rooms::normalize)
+            .transpose()
+            .map_err(Error::DefaultRoom)?;
+
+        let endpoints = listeners
+            .iter()
+            .filter_map(|(listener, endpoint)| Some((listener.local_addr().ok()?, *endpoint)))
+            .collect();
+        let mut metrics_listeners = Vec::new();
+        for addr in self.metrics_addrs {
+            metrics_listeners.push(bind(addr).map_err(|source| Error::Bind { addr, source })?);
+        }
+        let metrics_addrs = metrics_listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect();
+        let channel = Channel::new(self.channel_capacity, self.lag.history);
+        let history = Arc::new(Store::open(self.history, self.hooks.clone())?);
+        let accounts = Accounts::open(&self.auth)?;
+        let moderation = Moderation::open(&self.moderation)?;
+        let rooms = Rooms::new(self.channel_capacity, self.lag.history, history.clone());
+        let (shutdown, _) = watch::channel(false);
+
+        #[cfg(unix)]
+        let admin = match self.admin_socket {
+            Some(path) => match admin::bind(&path) {
+                Ok(listener) => Some((listener, path)),
+                Err(source) => return Err(Error::Admin { path, source }),
+            },
+            None => None,
+        };
+        #[cfg(not(unix))]
+        if let Some(path) = self.admin_socket {
+            return Err(Error::Admin {
+                path,
+                source: io::ErrorKind::Unsupported.into(),
+            });
+        }
+
+        Ok(Server {
+            listeners,
+            metrics_listeners,
+            #[cfg(unix)]
+            admin,
+            shutdown_timeout: self.shutdown_timeout,
+            shared: Arc::new(Shared {
+                channel,
+                lag: self.lag,
+                heartbeat: self.heartbeat,
+                admission: Admission::new(self.admission),
+                max_message_len: self.max_message_len,
+                limiter: Limiter::new(self.rate_limit),
+                hooks: self.hooks,
+                commands: self.commands,
+                users: Users::default(),
+                rooms,
+                history,
+                accounts,
+                moderation,
+                default_room,
+                peers: Mutex::new(HashMap::new()),
+                idle: Notify::new(),
+                shutdown,
+                tls: self.tls,
+                metrics: Metrics::default(),
+                endpoints,
+                metrics_addrs,
+            }),
+        })
+    }
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// Addresses of the plain TCP listeners.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.shared.addrs_of(Endpoint::Tcp)
+    }
+
+    /// Addresses of the WebSocket listeners.
+    pub fn websocket_addrs(&self) -> Vec<SocketAddr> {
+        self.shared.addrs_of(Endpoint::WebSocket)
+    }
+
+    /// Every listener's address and what it accepts.
+    pub fn endpoints(&self) -> Vec<(SocketAddr, Endpoint)> {
+        self.shared.endpoints.clone()
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle::new(self.shared.clone())
+    }
+
+    /// Accepts and serves connections until [`ServerHandle::shutdown`] is
+    /// called. Connected clients are then sent what is queued for them and
+    /// a shutdown notice, and room history is flushed to disk.
+    ///
+    /// Fails if clients are still connected after the shutdown timeout, or
+    /// if flushing history fails.
+    pub async fn run(self) -> Result<()> {
+        let mut accept_loops = JoinSet::new();
+        for (listener, endpoint) in self.listeners {
+            if let Ok(addr) = listener.local_addr() {
+                tracing::info!(%addr, ?endpoint, "listening");
+            }
+            accept_loops.spawn(accept_loop(listener, endpoint, self.shared.clone()));
+        }
+        for listener in self.metrics_listeners {
+            if let Ok(addr) = listener.local_addr() {
+                tracing::info!(%addr, "serving metrics");
+            }
+            accept_loops.spawn(metrics::serve(listener, self.shared.clone()));
+        }
+        #[cfg(unix)]
+        if let Some((listener, path)) = self.admin {
+            tracing::info!(path = %path.display(), "serving the admin socket");
+            accept_loops.spawn(admin::serve(listener, path, self.shared.clone()));
+        }
+        while accept_loops.join_next().await.is_some() {}
+
+        tracing::info!("stopped accepting connections");
+        let drained = time::timeout(self.shutdown_timeout, self.shared.wait_idle()).await;
+        self.shared.history.sync().await?;
+        match drained {
+            Ok(()) => Ok(()),
+            Err(_) => Err(Error::ShutdownTimeout {
+                remaining: self.shared.peers.lock().unwrap().len(),
+            }),
+        }
+    }
+}
+
+impl ServerHandle {
+    pub(crate) fn new(shared: Arc<Shared>) -> Self {
+        ServerHandle { shared }
+    }
+
+    /// Addresses of the plain TCP listeners.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.shared.addrs_of(Endpoint::Tcp)
+    }
+
+    /// Addresses of the WebSocket listeners.
+    pub fn websocket_addrs(&self) -> Vec<SocketAddr> {
+        self.shared.addrs_of(Endpoint::WebSocket)
+    }
+
+    /// Every listener's address and what it accepts.
+    pub fn endpoints(&self) -> Vec<(SocketAddr, Endpoint)> {
+        self.shared.endpoints.clone()
+    }
+
+    /// Addresses serving `/metrics`.
+    pub fn metrics_addrs(&self) -> Vec<SocketAddr> {
+        self.shared.metrics_addrs.clone()
+    }
+
+    /// Addresses of the currently connected clients.
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.shared.peers.lock().unwrap().keys().copied().collect()
+    }
+
+    /// The registered clients, sorted by nickname.
+    pub fn online(&self) -> Vec<Presence> {
+        self.shared.online()
+    }
+
+    /// The nickname `addr` registered, if it has finished the handshake.
+    pub fn nickname(&self, addr: SocketAddr) -> Option<String> {
+        self.shared.users.nick(addr)
+    }
+
+    /// Open rooms and their member counts, sorted by name.
+    pub fn rooms(&self) -> Vec<(String, usize)> {
+        self.shared.rooms.list()
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.shared.peers.lock().unwrap().len()
+    }
+
+    /// Every open connection with its traffic, oldest first.
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        let now = SystemTime::now();
+        let peers = self.shared.peers.lock().unwrap();
+        let mut clients: Vec<_> = peers
+            .iter()
+            .map(|(&addr, peer)| {
+                let age = now.duration_since(peer.connected_at).unwrap_or_default();
+                let messages = peer.stats.messages.load(Ordering::Relaxed);
+                ClientInfo {
+                    addr,
+                    endpoint: peer.endpoint,
+                    nick: self.shared.users.nick(addr),
+                    rooms: self.shared.rooms.rooms_of(addr),
+                    connected_secs: age.as_secs(),
+                    messages,
+                    rate: messages as f64 * 60.0 / age.as_secs_f64().max(1.0),
+                    lags: peer.stats.lags.load(Ordering::Relaxed),
+                }
+            })
+            .collect();
+        clients.sort_unstable_by_key(|client| std::cmp::Reverse(client.connected_secs));
+        clients
+    }
+
+    /// Open rooms and their members' nicknames, sorted by name.
+    pub fn room_members(&self) -> Vec<RoomInfo> {
+        self.shared
+            .rooms
+            .list()
+            .into_iter()
+            .map(|(name, _)| {
+                let members = self.shared.rooms.members(&name).unwrap_or_default();
+                let mut members: Vec<_> = members
+                    .into_iter()
+                    .filter_map(|addr| self.shared.users.nick(addr))
+                    .collect();
+                members.sort_unstable();
+                RoomInfo { name, members }
+            })
+            .collect()
+    }
+
+    /// Disconnects the client called `nick`, telling everyone it was kicked.
+    /// Returns its nickname as registered, or `None` if it is not online.
+    pub fn kick(&self, nick: &str, reason: Option<String>) -> Option<String> {
+        let notice = match &reason {
+            Some(reason) => format!("kicked by the server: {reason}"),
+            None => "kicked by the server".to_string(),
+        };
+        let kicked = self.shared.users.sanction(nick, Sanction::Remove(notice));
+        if let Some(nick) = &kicked {
+            tracing::info!(kicked = %nick, reason = reason.as_deref(), "kick");
+        }
+        kicked
+    }
+
+    /// Sends a system notice to every client.
+    pub fn notice(&self, text: impl Into<String>) {
+        let text = text.into();
+        tracing::info!(%text, "server notice");
+        let msg = Message::System { text };
+        self.shared.channel.send(SERVER_ADDR, msg);
+    }
+
+    /// Loads the config with the loader given to
+    /// [`ServerBuilder::reload_with`] and applies it like
+    /// [`reconfigure`](Self::reconfigure).
+    pub fn reload(&self) -> Result<()> {
+        let Some(loader) = &self.shared.hooks.reload else {
+            return Err(Error::NoReload);
+        };
+        let reloaded = loader().and_then(|config| self.reconfigure(&config));
+        match &reloaded {
+            Ok(()) => tracing::info!("config reloaded"),
+            Err(err) => tracing::error!("config not reloaded: {err}"),
+        }
+        Ok(reloaded?)
+    }
+
+    /// Applies the settings that can change while the server runs:
+    /// `admission`, `rate_limit` and the `moderation` operators and
+    /// password. Everything else needs a restart.
+    pub fn reconfigure(&self, config: &Config) -> std::result::Result<(), ConfigError> {
+        config.validate()?;
+        self.shared.admission.reconfigure(config.admission.clone());
+        self.shared.limiter.reconfigure(config.rate_limit.clone());
+        self.shared.moderation.reconfigure(&config.moderation);
+        Ok(())
+    }
+
+    /// Stops accepting connections and closes every open one.
+    pub fn shutdown(&self) {
+        if !self.shared.shutdown.send_replace(true) {
+            tracing::info!("shutting down");
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.shared.shutdown.borrow()
+    }
+}
+
+/// Resolves once [`ServerHandle::shutdown`] has been called.
+pub(crate) async fn stopped(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stop| *stop).await;
+}
+
+/// How long an accept loop waits after `accept` fails, usually with
+/// EMFILE/ENFILE/ENOBUFS: long enough for resources to free up rather than
+/// spinning, doubling while the failures go on.
+pub(crate) struct AcceptBackoff(Duration);
+
+impl AcceptBackoff {
+    const MIN: Duration = Duration::from_millis(5);
+    const MAX: Duration = Duration::from_secs(1);
+
+    pub fn new() -> Self {
+        AcceptBackoff(Self::MIN)
+    }
+
+    /// Called after a successful `accept`.
+    pub fn reset(&mut self) {
+        self.0 = Self::MIN;
+    }
+
+    /// Waits out the backoff. Returns `false` if the server shuts down
+    /// meanwhile.
+    pub async fn wait(&mut self, shutdown: &mut watch::Receiver<bool>) -> bool {
+        let wait = self.0;
+        self.0 = (wait * 2).min(Self::MAX);
+        tokio::select! {
+            _ = time::sleep(wait) => true,
+            _ = stopped(shutdown) => false,
+        }
+    }
+}
+
+/// Binds a listening socket. IPv6 sockets are made v6-only so that `[::]`
+/// and `0.0.0.0` can be listened on at the same time.
+fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+async fn accept_loop(listener: TcpListener, endpoint: Endpoint, shared: Arc<Shared>) {
+    let local_addr = listener
+        .local_addr()
+        .unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+    let mut shutdown = shared.shutdown.subscribe();
+    let mut backoff = AcceptBackoff::new();
+    loop {
+        let result = tokio::select! {
+            result = listener.accept() => result,
+            _ = stopped(&mut shutdown) => return,
+        };
+
+        match result {
+            Ok((socket, addr)) => {
+                backoff.reset();
+                if let Some(secs) = shared.heartbeat.keepalive_secs {
+                    let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(secs));
+                    // Best effort: the connection works without it.
+                    let _ = SockRef::from(&socket).set_tcp_keepalive(&keepalive);
+                }
+                let admitted = match shared.moderation.banned_ip(addr.ip()) {
+                    Some(_) => Err(Refusal::Banned),
+                    None => shared.admission.admit(addr.ip()),
+                };
+                match admitted {
+                    Ok(ticket) => {
+                        shared.metrics.accepted.fetch_add(1, Ordering::Relaxed);
+                        let span = tracing::info_span!(
+                            "connection",
+                            peer = %addr,
+                            ?endpoint,
+                            nick = field::Empty,
+                        );
+                        tokio::spawn(
+                            connection::handle(socket, addr, endpoint, shared.clone(), ticket)
+                                .instrument(span),
+                        );
+                    }
+                    Err(reason) => {
+                        shared.metrics.refused.fetch_add(1, Ordering::Relaxed);
+                        shared.hooks.error(&Error::Refused { peer: addr, reason });
+                        // Denied addresses get nothing; others are told why
+                        // unless too many are being told already.
+                        let polite = reason != Refusal::Denied;
+                        if let Some(ticket) = shared.admission.refusing().filter(|_| polite) {
+                            tokio::spawn(connection::refuse(
+                                socket,
+                                endpoint,
+                                shared.clone(),
+                                reason,
+                                ticket,
+                            ));
+                        }
+                    }
+                }
+            }
+            // The peer gave up before we got to it; nothing to report.
+            Err(err) if is_connection_error(&err) => {}
+            Err(source) => {
+                shared.hooks.error(&Error::Accept {
+                    listener: local_addr,
+                    source,
+                });
+                if !backoff.wait(&mut shutdown).await {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refused(configure: impl FnOnce(ServerBuilder) -> ServerBuilder) -> ConfigError {
+        match configure(Server::builder()).build() {
+            Err(Error::Config(err)) => err,
+            Err(err) => panic!("expected a config error, got {err}"),
+            Ok(_) => panic!("expected a config error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn zero_settings_are_refused_by_build() {
+        assert!(matches!(
+            refused(|builder| builder.channel_capacity(0)),
+            ConfigError::ZeroChannelCapacity
+        ));
+        assert!(matches!(
+            refused(|builder| builder.max_message_len(0)),
+            ConfigError::ZeroMaxMessageLen
+        ));
+        assert!(matches!(
+            refused(|builder| builder.lag_policy(LagPolicy {
+                disconnect_after: Some(0),
+                ..LagPolicy::default()
+            })),
+            ConfigError::ZeroDisconnectAfter
+        ));
+        assert!(matches!(
+            refused(|builder| builder.rate_limit(RateLimit {
+                rate: 0.0,
+                ..RateLimit::default()
+            })),
+            ConfigError::InvalidRateLimit
+        ));
+    }
+
+    #[test]
+    fn from_config_validates() {
+        let config = Config {
+            channel_capacity: 0,
+            ..Config::default()
+        };
+        assert!(matches!(
+            ServerBuilder::from_config(&config),
+            Err(ConfigError::ZeroChannelCapacity)
+        ));
+    }
+}
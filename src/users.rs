@@ -0,0 +1,174 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex, time::SystemTime};
+
+use thiserror::Error;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::{moderation::Sanction, protocol::Message};
+
+pub const MAX_NICK_LEN: usize = 16;
+
+/// Direct messages queued per client before further ones are refused.
+pub(crate) const INBOX_CAPACITY: usize = 32;
+
+/// Why a nickname was refused.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum NickError {
+    #[error("nicknames must be 1-{MAX_NICK_LEN} characters of letters, digits, `_` or `-`, starting with a letter")]
+    Invalid,
+    #[error("nickname `{0}` is already in use")]
+    Taken(String),
+}
+
+/// A registered client, as listed by `/who`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Presence {
+    pub nick: String,
+    pub addr: SocketAddr,
+    pub connected_at: SystemTime,
+}
+
+/// Why a direct message couldn't be delivered.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub(crate) enum DirectError {
+    #[error("{0} is not online")]
+    NotOnline(String),
+    #[error("{0} has too many unread messages")]
+    InboxFull(String),
+}
+
+/// The registered clients, by nickname and address. Names are unique
+/// ignoring ASCII case.
+#[derive(Debug, Default)]
+pub(crate) struct Users {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    by_name: HashMap<String, SocketAddr>,
+    by_addr: HashMap<SocketAddr, User>,
+}
+
+#[derive(Debug)]
+struct User {
+    nick: String,
+    inbox: mpsc::Sender<Message>,
+    sanctions: mpsc::UnboundedSender<Sanction>,
+}
+
+impl Users {
+    /// Registers `addr` as `nick`, with `inbox` receiving its direct
+    /// messages and `sanctions` what operators do to it.
+    pub fn register(
+        &self,
+        addr: SocketAddr,
+        nick: &str,
+        inbox: mpsc::Sender<Message>,
+        sanctions: mpsc::UnboundedSender<Sanction>,
+    ) -> Result<(), NickError> {
+        validate(nick)?;
+        let key = nick.to_ascii_lowercase();
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.by_name.contains_key(&key) {
+            return Err(NickError::Taken(nick.to_string()));
+        }
+        inner.by_name.insert(key, addr);
+        inner.by_addr.insert(
+            addr,
+            User {
+                nick: nick.to_string(),
+                inbox,
+                sanctions,
+            },
+        );
+        Ok(())
+    }
+
+    /// Changes the nickname of the already registered `addr`.
+    pub fn rename(&self, addr: SocketAddr, nick: &str) -> Result<(), NickError> {
+        validate(nick)?;
+        let key = nick.to_ascii_lowercase();
+
+        let mut inner = self.inner.lock().unwrap();
+        match inner.by_name.get(&key) {
+            Some(&owner) if owner != addr => return Err(NickError::Taken(nick.to_string())),
+            _ => {}
+        }
+        let Some(user) = inner.by_addr.get_mut(&addr) else {
+            return Ok(());
+        };
+        let old = std::mem::replace(&mut user.nick, nick.to_string());
+        inner.by_name.remove(&old.to_ascii_lowercase());
+        inner.by_name.insert(key, addr);
+        Ok(())
+    }
+
+    pub fn release(&self, addr: SocketAddr) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(user) = inner.by_addr.remove(&addr) {
+            inner.by_name.remove(&user.nick.to_ascii_lowercase());
+        }
+    }
+
+    pub fn nick(&self, addr: SocketAddr) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        inner.by_addr.get(&addr).map(|user| user.nick.clone())
+    }
+
+    /// Queues `msg` in the inbox of the client called `nick`.
+    pub fn send_direct(&self, nick: &str, msg: Message) -> Result<(), DirectError> {
+        let inner = self.inner.lock().unwrap();
+        let user = inner
+            .by_name
+            .get(&nick.to_ascii_lowercase())
+            .and_then(|addr| inner.by_addr.get(addr))
+            .ok_or_else(|| DirectError::NotOnline(nick.to_string()))?;
+        user.inbox.try_send(msg).map_err(|err| match err {
+            TrySendError::Full(_) => DirectError::InboxFull(user.nick.clone()),
+            TrySendError::Closed(_) => DirectError::NotOnline(user.nick.clone()),
+        })
+    }
+
+    /// Hands `sanction` to the client called `nick`. Returns its nickname as
+    /// registered, or `None` if it is not online.
+    pub fn sanction(&self, nick: &str, sanction: Sanction) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        let user = inner
+            .by_name
+            .get(&nick.to_ascii_lowercase())
+            .and_then(|addr| inner.by_addr.get(addr))?;
+        user.sanctions.send(sanction).ok()?;
+        Some(user.nick.clone())
+    }
+
+    /// Hands `sanction` to every client `matches` picks by nickname and
+    /// address. Returns their nicknames.
+    pub fn sanction_all(
+        &self,
+        matches: impl Fn(&str, SocketAddr) -> bool,
+        sanction: Sanction,
+    ) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .by_addr
+            .iter()
+            .filter(|(&addr, user)| matches(&user.nick, addr))
+            .filter(|(_, user)| user.sanctions.send(sanction.clone()).is_ok())
+            .map(|(_, user)| user.nick.clone())
+            .collect()
+    }
+}
+
+/// Checks that `nick` is a valid nickname.
+pub(crate) fn validate(nick: &str) -> Result<(), NickError> {
+    let mut chars = nick.chars();
+    let valid = nick.len() <= MAX_NICK_LEN
+        && chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if valid {
+        Ok(())
+    } else {
+        Err(NickError::Invalid)
+    }
+}
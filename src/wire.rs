@@ -0,0 +1,335 @@
+use std::{future::Future, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time,
+};
+
+use crate::{
+    error::ConnectionError,
+    protocol::{self, Message, ProtocolError},
+};
+
+/// How long to wait for a framed client's preamble before assuming the
+/// client speaks plain text.
+const DETECT_TIMEOUT: Duration = Duration::from_millis(200);
+
+const PREAMBLE_LEN: usize = 5;
+
+/// Where a connection's incoming messages come from.
+pub(crate) trait Source: Send {
+    /// Returns the next message, or `None` once the client closes the
+    /// connection. Must be cancel safe.
+    fn next(&mut self) -> impl Future<Output = Result<Option<Message>, ConnectionError>> + Send;
+
+    /// Whether reading can carry on after [`ConnectionError::TooLarge`].
+    fn skips_oversized(&self) -> bool {
+        true
+    }
+}
+
+/// Where a connection's outgoing messages go.
+pub(crate) trait Sink: Send {
+    fn send(&mut self, msg: &Message) -> impl Future<Output = Result<(), ConnectionError>> + Send;
+
+    /// Flushes anything buffered and closes the connection cleanly.
+    fn close(&mut self) -> impl Future<Output = Result<(), ConnectionError>> + Send;
+
+    /// Whether the client is sent [`Message::Ping`]s, and so can be
+    /// expected to answer them.
+    fn pings(&self) -> bool {
+        true
+    }
+
+    fn system(
+        &mut self,
+        text: impl Into<String>,
+    ) -> impl Future<Output = Result<(), ConnectionError>> + Send {
+        let msg = Message::system(text);
+        async move { self.send(&msg).await }
+    }
+
+    fn error(
+        &mut self,
+        text: impl Into<String>,
+    ) -> impl Future<Output = Result<(), ConnectionError>> + Send {
+        let msg = Message::error(text);
+        async move { self.send(&msg).await }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Mode {
+    Text,
+    Framed,
+}
+
+/// Reads [`Message`]s from a byte stream in either protocol. Text lines
+/// arrive as [`Message::chat`].
+///
+/// Lines and frames longer than `max_len` are skipped without being
+/// buffered and reported as [`ConnectionError::TooLarge`], after which the
+/// reader can carry on.
+pub(crate) struct WireReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    mode: Mode,
+    eof: bool,
+    max_len: usize,
+    skip: Skip,
+}
+
+/// What remains of an oversized message being thrown away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Skip {
+    Nothing,
+    /// Everything up to and including the next newline.
+    Line,
+    Bytes(usize),
+}
+
+pub(crate) struct WireWriter<W> {
+    inner: W,
+    mode: Mode,
+}
+
+impl<R: AsyncRead + Unpin> WireReader<R> {
+    pub fn new(inner: R, max_len: usize) -> Self {
+        WireReader {
+            inner,
+            buf: Vec::new(),
+            mode: Mode::Text,
+            eof: false,
+            max_len,
+            skip: Skip::Nothing,
+        }
+    }
+
+    /// Works out which protocol the client speaks. Framed clients send the
+    /// preamble straight away; anything else, including silence, is text.
+    pub async fn detect(&mut self) -> Result<Mode, ConnectionError> {
+        if let Ok(result) = time::timeout(DETECT_TIMEOUT, self.fill()).await {
+            result?;
+        }
+        if self.buf.first() != Some(&0) {
+            return Ok(Mode::Text);
+        }
+
+        while self.buf.len() < PREAMBLE_LEN && !self.eof {
+            self.fill().await?;
+        }
+        let preamble = self
+            .buf
+            .get(..PREAMBLE_LEN)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(ProtocolError::BadMagic)?;
+        protocol::check_preamble(preamble)?;
+        self.buf.drain(..PREAMBLE_LEN);
+        self.mode = Mode::Framed;
+        Ok(self.mode)
+    }
+
+    async fn fill(&mut self) -> Result<(), ConnectionError> {
+        let n = self
+            .inner
+            .read_buf(&mut self.buf)
+            .await
+            .map_err(ConnectionError::Read)?;
+        if n == 0 {
+            self.eof = true;
+        }
+        Ok(())
+    }
+
+    fn decode(&mut self) -> Result<Option<Message>, ConnectionError> {
+        if !self.skip() {
+            return Ok(None);
+        }
+        let too_large = ConnectionError::TooLarge { max: self.max_len };
+        match self.mode {
+            Mode::Text => {
+                let end = match self.buf.iter().position(|&b| b == b'\n') {
+                    Some(pos) => pos + 1,
+                    None if self.buf.len() > self.max_len => {
+                        self.buf.clear();
+                        self.skip = Skip::Line;
+                        return Err(too_large);
+                    }
+                    // A final line without a newline.
+                    None if self.eof && !self.buf.is_empty() => self.buf.len(),
+                    None => return Ok(None),
+                };
+                let bytes: Vec<u8> = self.buf.drain(..end).collect();
+                let line = String::from_utf8(bytes).map_err(|_| ConnectionError::InvalidUtf8)?;
+                let line = line.trim_end_matches(['\r', '\n']);
+                if line.len() > self.max_len {
+                    return Err(too_large);
+                }
+                Ok(Some(Message::chat(line)))
+            }
+            Mode::Framed => match protocol::decode(&self.buf, self.max_len) {
+                Ok(Some((msg, used))) => {
+                    self.buf.drain(..used);
+                    Ok(Some(msg))
+                }
+                Ok(None) => Ok(None),
+                Err(ProtocolError::FrameTooLarge { len, .. }) => {
+                    self.buf.drain(..protocol::LEN_PREFIX);
+                    self.skip = Skip::Bytes(len);
+                    Err(too_large)
+                }
+                Err(err) => Err(err.into()),
+            },
+        }
+    }
+
+    /// Throws away what is buffered of an oversized message. Returns whether
+    /// all of it is gone.
+    fn skip(&mut self) -> bool {
+        match self.skip {
+            Skip::Nothing => return true,
+            Skip::Line => match self.buf.iter().position(|&b| b == b'\n') {
+                Some(pos) => {
+                    self.buf.drain(..=pos);
+                    self.skip = Skip::Nothing;
+                }
+                None => self.buf.clear(),
+            },
+            Skip::Bytes(left) => {
+                let n = left.min(self.buf.len());
+                self.buf.drain(..n);
+                self.skip = match left - n {
+                    0 => Skip::Nothing,
+                    left => Skip::Bytes(left),
+                };
+            }
+        }
+        self.skip == Skip::Nothing
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send> Source for WireReader<R> {
+    // Cancel safe: bytes are buffered until a whole message has arrived, so
+    // nothing is lost if another `select!` branch wins.
+    async fn next(&mut self) -> Result<Option<Message>, ConnectionError> {
+        loop {
+            if let Some(msg) = self.decode()? {
+                return Ok(Some(msg));
+            }
+            if self.eof {
+                return Ok(None);
+            }
+            self.fill().await?;
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> WireWriter<W> {
+    pub fn new(inner: W, mode: Mode) -> Self {
+        WireWriter { inner, mode }
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send> Sink for WireWriter<W> {
+    async fn send(&mut self, msg: &Message) -> Result<(), ConnectionError> {
+        let bytes = match self.mode {
+            Mode::Text => match msg.to_text() {
+                Some(line) => format!("{line}\n").into_bytes(),
+                None => return Ok(()),
+            },
+            Mode::Framed => protocol::encode(msg),
+        };
+        self.inner
+            .write_all(&bytes)
+            .await
+            .map_err(ConnectionError::Write)
+    }
+
+    async fn close(&mut self) -> Result<(), ConnectionError> {
+        self.inner.shutdown().await.map_err(ConnectionError::Write)
+    }
+
+    fn pings(&self) -> bool {
+        self.mode == Mode::Framed
+    }
+}
+
+/// Passes messages on to another sink, marking errors as answering the
+/// client message `id`.
+pub(crate) struct Reply<'a, W> {
+    pub inner: &'a mut W,
+    pub id: u64,
+}
+
+impl<W: Sink> Sink for Reply<'_, W> {
+    async fn send(&mut self, msg: &Message) -> Result<(), ConnectionError> {
+        match msg {
+            Message::Error { id: None, text } => {
+                let msg = Message::Error {
+                    id: Some(self.id),
+                    text: text.clone(),
+                };
+                self.inner.send(&msg).await
+            }
+            msg => self.inner.send(msg).await,
+        }
+    }
+
+    async fn close(&mut self) -> Result<(), ConnectionError> {
+        self.inner.close().await
+    }
+
+    fn pings(&self) -> bool {
+        self.inner.pings()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn next<R: AsyncRead + Unpin + Send>(reader: &mut WireReader<R>) -> Option<Message> {
+        reader.next().await.unwrap()
+    }
+
+    fn too_large<T: std::fmt::Debug>(result: Result<T, ConnectionError>) -> bool {
+        matches!(result, Err(ConnectionError::TooLarge { .. }))
+    }
+
+    #[tokio::test]
+    async fn long_lines_are_skipped_to_the_next_newline() {
+        // The long line arrives in two reads, so the reader has to remember
+        // to keep skipping.
+        let input = (&b"hi\nthis line is "[..]).chain(&b"far too long\nok"[..]);
+        let mut reader = WireReader::new(input, 8);
+        assert_eq!(reader.detect().await.unwrap(), Mode::Text);
+
+        assert_eq!(next(&mut reader).await, Some(Message::chat("hi")));
+        assert!(too_large(reader.next().await));
+        assert_eq!(next(&mut reader).await, Some(Message::chat("ok")));
+        assert_eq!(next(&mut reader).await, None);
+    }
+
+    #[tokio::test]
+    async fn long_lines_that_fit_the_buffer_are_refused_too() {
+        let mut reader = WireReader::new(&b"123456789\n12345678\n"[..], 8);
+        assert!(too_large(reader.next().await));
+        assert_eq!(next(&mut reader).await, Some(Message::chat("12345678")));
+    }
+
+    #[tokio::test]
+    async fn long_frames_are_skipped_by_length() {
+        let long = protocol::encode(&Message::chat("x".repeat(64)));
+        let (first, rest) = long.split_at(20);
+        let mut tail = rest.to_vec();
+        tail.extend_from_slice(&protocol::encode(&Message::Ack { id: 1 }));
+        let mut head = protocol::preamble().to_vec();
+        head.extend_from_slice(first);
+
+        let mut reader = WireReader::new((&head[..]).chain(&tail[..]), 32);
+        assert_eq!(reader.detect().await.unwrap(), Mode::Framed);
+        assert!(too_large(reader.next().await));
+        assert_eq!(next(&mut reader).await, Some(Message::Ack { id: 1 }));
+        assert_eq!(next(&mut reader).await, None);
+    }
+}
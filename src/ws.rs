@@ -0,0 +1,101 @@
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::{
+    tungstenite::{
+        self, error::ProtocolError as ProtocolViolation, protocol::WebSocketConfig,
+        Message as WsMessage,
+    },
+    WebSocketStream,
+};
+
+use crate::{
+    error::ConnectionError,
+    protocol::{Message, ProtocolError},
+    wire::{Sink, Source},
+};
+
+/// Incoming messages from a WebSocket client. Text frames holding a JSON
+/// [`Message`] are taken as is; any other text is a chat line.
+pub(crate) struct WsSource<S> {
+    stream: SplitStream<WebSocketStream<S>>,
+    max_len: usize,
+}
+
+/// Outgoing messages to a WebSocket client, one JSON text frame each.
+pub(crate) struct WsSink<S> {
+    sink: SplitSink<WebSocketStream<S>, WsMessage>,
+}
+
+/// Performs the WebSocket handshake on an accepted stream. Messages longer
+/// than `max_len` are refused before they are buffered in full.
+pub(crate) async fn accept<S>(
+    stream: S,
+    max_len: usize,
+) -> Result<(WsSource<S>, WsSink<S>), ConnectionError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let config = WebSocketConfig::default()
+        .max_message_size(Some(max_len))
+        .max_frame_size(Some(max_len));
+    let ws = tokio_tungstenite::accept_async_with_config(stream, Some(config)).await?;
+    let (sink, stream) = ws.split();
+    Ok((WsSource { stream, max_len }, WsSink { sink }))
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Source for WsSource<S> {
+    async fn next(&mut self) -> Result<Option<Message>, ConnectionError> {
+        loop {
+            let frame = match self.stream.next().await {
+                None => return Ok(None),
+                // A client vanishing without a close frame is no worse than
+                // a TCP client hanging up.
+                Some(Err(
+                    tungstenite::Error::ConnectionClosed
+                    | tungstenite::Error::Protocol(ProtocolViolation::ResetWithoutClosingHandshake),
+                )) => return Ok(None),
+                Some(Err(tungstenite::Error::Capacity(_))) => {
+                    return Err(ConnectionError::TooLarge { max: self.max_len })
+                }
+                Some(Err(err)) => return Err(err.into()),
+                Some(Ok(frame)) => frame,
+            };
+            match frame {
+                WsMessage::Text(text) => {
+                    let msg = serde_json::from_str(&text)
+                        .unwrap_or_else(|_| Message::chat(text.trim_end_matches(['\r', '\n'])));
+                    return Ok(Some(msg));
+                }
+                WsMessage::Binary(bytes) => {
+                    let msg = serde_json::from_slice(&bytes).map_err(ProtocolError::from)?;
+                    return Ok(Some(msg));
+                }
+                WsMessage::Close(_) => return Ok(None),
+                // Pings are answered by tungstenite itself.
+                WsMessage::Ping(_) | WsMessage::Pong(_) | WsMessage::Frame(_) => {}
+            }
+        }
+    }
+
+    /// tungstenite can't resume after an oversized message, so the first
+    /// one ends the session.
+    fn skips_oversized(&self) -> bool {
+        false
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Sink for WsSink<S> {
+    async fn send(&mut self, msg: &Message) -> Result<(), ConnectionError> {
+        let text = serde_json::to_string(msg).expect("messages always serialize");
+        self.sink.send(WsMessage::text(text)).await?;
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<(), ConnectionError> {
+        self.sink.close().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+#![cfg(unix)]
+
+mod support;
+
+use std::{fs, os::unix::fs::PermissionsExt, path::PathBuf};
+
+use msg_server::admin::{AdminRequest, AdminResponse};
+use support::{TestServer, TIMEOUT};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+    time,
+};
+
+fn socket_path(test: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("msg_server-{test}-{}.sock", std::process::id()))
+}
+
+/// Sends `request` as one line and reads the response, if the server sends
+/// one before hanging up.
+async fn ask(path: &PathBuf, request: AdminRequest) -> Option<AdminResponse> {
+    let mut stream = BufReader::new(UnixStream::connect(path).await.unwrap());
+    let request = serde_json::to_vec(&request).unwrap();
+    stream.write_all(&request).await.unwrap();
+    stream.write_all(b"\n").await.unwrap();
+    let mut line = String::new();
+    time::timeout(TIMEOUT, stream.read_line(&mut line))
+        .await
+        .expect("timed out waiting for a response")
+        .unwrap();
+    (!line.is_empty()).then(|| serde_json::from_str(&line).unwrap())
+}
+
+#[tokio::test]
+async fn socket_is_private_from_the_start() {
+    let path = socket_path("admin-private");
+    let server = TestServer::with(|builder| builder.admin_socket(&path)).await;
+
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let staging = format!("{}.{}", path.display(), std::process::id());
+    assert!(
+        fs::symlink_metadata(staging).is_err(),
+        "the staging directory should be gone"
+    );
+    assert!(matches!(
+        ask(&path, AdminRequest::Clients).await,
+        Some(AdminResponse::Clients { .. })
+    ));
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn long_requests_end_the_session() {
+    let path = socket_path("admin-long");
+    let server = TestServer::with(|builder| builder.admin_socket(&path)).await;
+
+    let mut stream = BufReader::new(UnixStream::connect(&path).await.unwrap());
+    let request = vec![b' '; 100 * 1024];
+    // The server may hang up before reading all of it.
+    let _ = stream.write_all(&request).await;
+    let mut line = String::new();
+    time::timeout(TIMEOUT, stream.read_line(&mut line))
+        .await
+        .expect("timed out waiting for a response")
+        .unwrap();
+    match serde_json::from_str(&line).unwrap() {
+        AdminResponse::Error { text } => assert!(text.starts_with("request longer than")),
+        response => panic!("expected an error, got {response:?}"),
+    }
+    line.clear();
+    let read = time::timeout(TIMEOUT, stream.read_line(&mut line)).await;
+    assert!(
+        matches!(read, Ok(Ok(0)) | Ok(Err(_))),
+        "the session should end"
+    );
+
+    assert!(ask(&path, AdminRequest::Rooms).await.is_some());
+    server.stop().await.unwrap();
+}
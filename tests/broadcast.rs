@@ -0,0 +1,70 @@
+mod support;
+
+use support::{Step, TestServer};
+
+#[tokio::test]
+async fn messages_fan_out_to_every_other_member() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    alice.expect("*** bob has connected").await;
+    let mut carol = server.connect("carol").await;
+    alice.expect("*** carol has connected").await;
+    bob.expect("*** carol has connected").await;
+    alice.join("#chat").await;
+    bob.join("#chat").await;
+    alice.expect("*** bob has joined #chat").await;
+    carol.join("#chat").await;
+    alice.expect("*** carol has joined #chat").await;
+    bob.expect("*** carol has joined #chat").await;
+
+    alice.send("hello").await;
+    bob.expect("[#chat] <alice> hello").await;
+    carol.expect("[#chat] <alice> hello").await;
+    carol.send("hi both").await;
+    alice.expect("[#chat] <carol> hi both").await;
+    bob.expect("[#chat] <carol> hi both").await;
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn senders_do_not_get_their_own_messages_back() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    alice.expect("*** bob has connected").await;
+    alice.join("#chat").await;
+    bob.join("#chat").await;
+    alice.expect("*** bob has joined #chat").await;
+
+    alice.send("hello").await;
+    bob.run(&[
+        Step::Expect("[#chat] <alice> hello"),
+        Step::Send("hi alice"),
+    ])
+    .await;
+    // Bob's reply was broadcast after Alice's message, so an echo of hers
+    // would arrive first.
+    alice.expect("[#chat] <bob> hi alice").await;
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn members_of_other_rooms_are_not_sent_messages() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    alice.expect("*** bob has connected").await;
+    alice.join("#chat").await;
+    bob.join("#other").await;
+
+    alice.send("only for #chat").await;
+    alice.send("/join #other").await;
+    alice.expect("*** joined #other").await;
+    // Alice's join follows her message, so bob would have seen it first.
+    bob.expect("*** alice has joined #other").await;
+
+    server.stop().await.unwrap();
+}
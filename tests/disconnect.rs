@@ -0,0 +1,75 @@
+mod support;
+
+use std::time::Duration;
+
+use support::{TestServer, TIMEOUT};
+use tokio::time;
+
+#[tokio::test]
+async fn connections_are_announced() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    alice.expect("*** bob has connected").await;
+
+    // Nobody is told about their own arrival.
+    bob.send("/who").await;
+    let who = bob.recv().await.unwrap();
+    assert!(who.starts_with("*** 2 online: "), "{who}");
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn dropped_connections_are_announced_and_cleaned_up() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+    let bob = server.connect("bob").await;
+    alice.expect("*** bob has connected").await;
+    assert_eq!(server.handle().connection_count(), 2);
+
+    drop(bob);
+    alice.expect("*** bob has quit (quit)").await;
+    time::timeout(TIMEOUT, async {
+        while server.handle().connection_count() > 1 {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("bob's connection should be cleaned up");
+
+    // The nickname is free again.
+    server.connect("bob").await;
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn quitting_clients_give_their_reason() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    alice.expect("*** bob has connected").await;
+
+    bob.send("/quit gone fishing").await;
+    assert_eq!(bob.expect_closed().await, Vec::<String>::new());
+    alice.expect("*** bob has quit (gone fishing)").await;
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn shutting_down_disconnects_everyone() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+
+    server.stop().await.unwrap();
+    for client in [&mut alice, &mut bob] {
+        let lines = client.expect_closed().await;
+        assert!(
+            lines.contains(&"*** server is shutting down".to_string()),
+            "{lines:?}"
+        );
+    }
+}
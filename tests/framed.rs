@@ -0,0 +1,193 @@
+mod support;
+
+use msg_server::protocol::Message;
+use support::{Step, TestServer};
+
+fn say(id: u64, room: &str, text: &str) -> Message {
+    Message::Chat {
+        id: Some(id),
+        room: Some(room.to_string()),
+        from: None,
+        to: None,
+        text: text.to_string(),
+        ts: None,
+    }
+}
+
+#[tokio::test]
+async fn framed_clients_are_acked_and_talk_to_text_clients() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect_framed("alice").await;
+    let mut bob = server.connect("bob").await;
+    alice
+        .expect(Message::Online {
+            nick: "bob".to_string(),
+        })
+        .await;
+    alice.join("#chat").await;
+    bob.join("#chat").await;
+    alice
+        .expect(Message::Join {
+            room: "#chat".to_string(),
+            nick: Some("bob".to_string()),
+            since: None,
+        })
+        .await;
+
+    alice
+        .run(&[
+            Step::Send(say(7, "#chat", "hello")),
+            Step::Expect(Message::Ack { id: 7 }),
+        ])
+        .await;
+    bob.expect("[#chat] <alice> hello").await;
+
+    bob.send("hi alice").await;
+    let Message::Chat {
+        id,
+        room,
+        from,
+        text,
+        ts,
+        ..
+    } = alice.recv().await.unwrap()
+    else {
+        panic!("expected bob's message");
+    };
+    assert_eq!(
+        (room.as_deref(), from.as_deref(), text.as_str()),
+        (Some("#chat"), Some("bob"), "hi alice")
+    );
+    assert!(id.is_some() && ts.is_some());
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn errors_name_the_message_they_answer() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect_framed("alice").await;
+
+    alice
+        .run(&[
+            Step::Send(say(4, "#chat", "anyone?")),
+            Step::Expect(Message::Error {
+                id: Some(4),
+                text: "you are not in #chat".to_string(),
+            }),
+            Step::Expect(Message::Ack { id: 4 }),
+        ])
+        .await;
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn oversized_frames_are_dropped_without_losing_the_connection() {
+    let server = TestServer::with(|builder| builder.max_message_len(64)).await;
+    let mut alice = server.connect_framed("alice").await;
+    alice.join("#chat").await;
+
+    alice.send(&say(1, "#chat", &"x".repeat(100))).await;
+    alice
+        .expect(Message::error(
+            "message exceeds the 64 byte limit; message dropped",
+        ))
+        .await;
+    alice
+        .run(&[
+            Step::Send(say(2, "#chat", "short")),
+            Step::Expect(Message::Ack { id: 2 }),
+        ])
+        .await;
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn malformed_frames_end_the_session() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect_framed("bob").await;
+    alice.expect("*** bob has connected").await;
+
+    let mut frame = 5u32.to_be_bytes().to_vec();
+    frame.extend_from_slice(b"nope!");
+    bob.write_raw(&frame).await;
+    let msgs = bob.expect_closed().await;
+    assert!(
+        matches!(msgs.as_slice(), [Message::Error { id: None, text }] if text.starts_with("invalid message")),
+        "{msgs:?}"
+    );
+    let line = alice.recv().await.unwrap();
+    assert!(
+        line.starts_with("*** bob has quit (invalid message"),
+        "{line}"
+    );
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn websocket_clients_chat_in_json() {
+    let server =
+        TestServer::with(|builder| builder.bind_websocket(([127, 0, 0, 1], 0).into())).await;
+    let mut alice = server.connect_websocket("alice").await;
+    let mut bob = server.connect("bob").await;
+    alice
+        .expect(Message::Online {
+            nick: "bob".to_string(),
+        })
+        .await;
+    alice.send("/join #chat").await;
+    alice
+        .expect(Message::Join {
+            room: "#chat".to_string(),
+            nick: None,
+            since: None,
+        })
+        .await;
+    bob.join("#chat").await;
+    alice
+        .expect(Message::Join {
+            room: "#chat".to_string(),
+            nick: Some("bob".to_string()),
+            since: None,
+        })
+        .await;
+
+    // Plain text frames are chat lines; JSON frames are messages.
+    alice.send("hello").await;
+    bob.expect("[#chat] <alice> hello").await;
+    alice
+        .send(&serde_json::to_string(&say(3, "#chat", "and again")).unwrap())
+        .await;
+    alice.expect(Message::Ack { id: 3 }).await;
+    bob.expect("[#chat] <alice> and again").await;
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn oversized_websocket_messages_end_the_session_with_the_reason() {
+    let server = TestServer::with(|builder| {
+        builder
+            .bind_websocket(([127, 0, 0, 1], 0).into())
+            .max_message_len(64)
+    })
+    .await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect_websocket("bob").await;
+    alice.expect("*** bob has connected").await;
+
+    bob.send(&"x".repeat(100)).await;
+    assert_eq!(
+        bob.expect_closed().await,
+        vec![Message::error("message exceeds the 64 byte limit")]
+    );
+    alice
+        .expect("*** bob has quit (message exceeds the 64 byte limit)")
+        .await;
+
+    server.stop().await.unwrap();
+}
@@ -0,0 +1,76 @@
+mod support;
+
+use msg_server::LagPolicy;
+use support::{TestClient, TestServer};
+
+/// Sends `count` server notices at once. Tests run on a single-threaded
+/// runtime, so no client reads any of them until the test next awaits.
+fn flood(server: &TestServer, count: usize) {
+    for i in 0..count {
+        server.handle().notice(format!("notice {i}"));
+    }
+}
+
+async fn expect_notices(client: &mut TestClient, range: std::ops::Range<usize>) {
+    for i in range {
+        client.expect(&format!("*** notice {i}")).await;
+    }
+}
+
+#[tokio::test]
+async fn lagging_clients_are_told_how_much_they_missed() {
+    let server = TestServer::with(|builder| builder.channel_capacity(4)).await;
+    let mut alice = server.connect("alice").await;
+    // Makes sure Alice's session is running and subscribed.
+    alice.join("#chat").await;
+
+    flood(&server, 20);
+    alice.expect("*** you missed 16 messages").await;
+    expect_notices(&mut alice, 16..20).await;
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn lagging_clients_catch_up_from_history() {
+    let server = TestServer::with(|builder| {
+        builder.channel_capacity(4).lag_policy(LagPolicy {
+            history: 32,
+            ..LagPolicy::default()
+        })
+    })
+    .await;
+    let mut alice = server.connect("alice").await;
+    alice.join("#chat").await;
+
+    flood(&server, 20);
+    expect_notices(&mut alice, 0..20).await;
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn clients_that_keep_lagging_are_disconnected() {
+    let server = TestServer::with(|builder| {
+        builder.channel_capacity(4).lag_policy(LagPolicy {
+            disconnect_after: Some(2),
+            ..LagPolicy::default()
+        })
+    })
+    .await;
+    let mut alice = server.connect("alice").await;
+    alice.join("#chat").await;
+
+    flood(&server, 20);
+    alice.expect("*** you missed 16 messages").await;
+    expect_notices(&mut alice, 16..20).await;
+
+    flood(&server, 20);
+    alice.expect("*** you missed 16 messages").await;
+    assert_eq!(
+        alice.expect_closed().await,
+        ["*** disconnected: too far behind"]
+    );
+
+    server.stop().await.unwrap();
+}
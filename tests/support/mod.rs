@@ -0,0 +1,392 @@
+//! Starts servers on ephemeral ports and drives scripted plain-text, framed
+//! and WebSocket clients against them.
+
+// Each test binary uses a different part of this module.
+#![allow(dead_code)]
+
+use std::{net::SocketAddr, time::Duration};
+
+use futures_util::{SinkExt, StreamExt};
+use msg_server::{
+    protocol::{self, Message, DEFAULT_MAX_FRAME_LEN, VERSION},
+    RateLimit, Server, ServerBuilder, ServerHandle,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    task::JoinHandle,
+    time,
+};
+use tokio_tungstenite::{tungstenite::Message as WsMessage, MaybeTlsStream, WebSocketStream};
+
+/// How long a client waits for an expected line before the test fails.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server running in the test's runtime.
+pub struct TestServer {
+    addr: SocketAddr,
+    handle: ServerHandle,
+    task: JoinHandle<msg_server::Result<()>>,
+}
+
+impl TestServer {
+    /// Starts a server on `127.0.0.1:0` with no default room and no rate
+    /// limit.
+    pub async fn start() -> TestServer {
+        TestServer::with(|builder| builder).await
+    }
+
+    /// Starts a server like [`start`](Self::start), with further settings
+    /// applied by `configure`.
+    pub async fn with(configure: impl FnOnce(ServerBuilder) -> ServerBuilder) -> TestServer {
+        let builder = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .default_room(None)
+            .rate_limit(RateLimit {
+                enabled: false,
+                ..RateLimit::default()
+            })
+            .shutdown_timeout(TIMEOUT);
+        let server = configure(builder).build().expect("server should build");
+        let addr = server.local_addrs()[0];
+        assert_ne!(addr.port(), 0, "the listener should have a real port");
+        let handle = server.handle();
+        let task = tokio::spawn(server.run());
+        TestServer { addr, handle, task }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The first WebSocket listener's address. The server must have been
+    /// started with one.
+    pub fn websocket_addr(&self) -> SocketAddr {
+        self.handle.websocket_addrs()[0]
+    }
+
+    pub fn handle(&self) -> &ServerHandle {
+        &self.handle
+    }
+
+    /// Connects a client and registers it as `nick`.
+    pub async fn connect(&self, nick: &str) -> TestClient {
+        let mut client = TestClient::connect(self.addr).await;
+        // Speaking first saves waiting for the server to give up on a
+        // framed preamble.
+        client.send(nick).await;
+        client.expect("*** welcome! choose a nickname:").await;
+        client
+            .expect(&format!("*** you are now known as {nick}"))
+            .await;
+        client
+    }
+
+    /// Connects a framed client and registers it as `nick`.
+    pub async fn connect_framed(&self, nick: &str) -> FramedClient {
+        let mut client = FramedClient::connect(self.addr).await;
+        client.send(&nick_request(nick)).await;
+        client.expect_registered(nick).await;
+        client
+    }
+
+    /// Connects a WebSocket client and registers it as `nick`.
+    pub async fn connect_websocket(&self, nick: &str) -> WsClient {
+        let mut client = WsClient::connect(self.websocket_addr()).await;
+        client.send(nick).await;
+        client.expect_registered(nick).await;
+        client
+    }
+
+    /// Shuts the server down and waits for it to finish.
+    pub async fn stop(self) -> msg_server::Result<()> {
+        self.handle.shutdown();
+        time::timeout(TIMEOUT * 2, self.task)
+            .await
+            .expect("server should stop")
+            .expect("server task should not panic")
+    }
+}
+
+/// One step of a client's `run` script: a line for [`TestClient`], a
+/// [`Message`] for [`FramedClient`].
+#[derive(Debug, Clone)]
+pub enum Step<T> {
+    Send(T),
+    /// The next line or message received must be exactly this.
+    Expect(T),
+}
+
+fn nick_request(nick: &str) -> Message {
+    Message::Nick {
+        nick: nick.to_string(),
+        old: None,
+    }
+}
+
+/// A client speaking the plain-text protocol, as `nc` would.
+pub struct TestClient {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    write: OwnedWriteHalf,
+}
+
+impl TestClient {
+    /// Connects without registering.
+    pub async fn connect(addr: SocketAddr) -> TestClient {
+        let stream = TcpStream::connect(addr).await.expect("should connect");
+        let (read, write) = stream.into_split();
+        TestClient {
+            lines: BufReader::new(read).lines(),
+            write,
+        }
+    }
+
+    pub async fn send(&mut self, line: &str) {
+        self.write
+            .write_all(format!("{line}\n").as_bytes())
+            .await
+            .expect("should send");
+    }
+
+    /// The next line, or `None` once the server closes the connection.
+    pub async fn recv(&mut self) -> Option<String> {
+        time::timeout(TIMEOUT, self.lines.next_line())
+            .await
+            .expect("timed out waiting for a line")
+            .expect("should read")
+    }
+
+    /// Asserts the next line is `expected`.
+    pub async fn expect(&mut self, expected: &str) {
+        assert_eq!(self.recv().await.as_deref(), Some(expected));
+    }
+
+    /// Reads until a line satisfying `matches` arrives and returns it.
+    pub async fn skip_until(&mut self, matches: impl Fn(&str) -> bool) -> String {
+        loop {
+            match self.recv().await {
+                Some(line) if matches(&line) => return line,
+                Some(_) => {}
+                None => panic!("connection closed before the expected line"),
+            }
+        }
+    }
+
+    /// Reads until the server closes the connection and returns the lines
+    /// received on the way.
+    pub async fn expect_closed(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Some(line) = self.recv().await {
+            lines.push(line);
+        }
+        lines
+    }
+
+    /// Joins `room` and waits for the server to confirm it.
+    pub async fn join(&mut self, room: &str) {
+        self.send(&format!("/join {room}")).await;
+        self.expect(&format!("*** joined {room}")).await;
+    }
+
+    /// Sends and expects lines in order.
+    pub async fn run(&mut self, script: &[Step<&str>]) {
+        for step in script {
+            match *step {
+                Step::Send(line) => self.send(line).await,
+                Step::Expect(line) => self.expect(line).await,
+            }
+        }
+    }
+}
+
+/// A client speaking the framed protocol, as `msg_client` does. Pings are
+/// answered without being returned.
+pub struct FramedClient {
+    read: OwnedReadHalf,
+    write: OwnedWriteHalf,
+    buf: Vec<u8>,
+}
+
+impl FramedClient {
+    /// Connects and sends the preamble, without registering.
+    pub async fn connect(addr: SocketAddr) -> FramedClient {
+        let stream = TcpStream::connect(addr).await.expect("should connect");
+        let (read, mut write) = stream.into_split();
+        write
+            .write_all(&protocol::preamble())
+            .await
+            .expect("should send the preamble");
+        FramedClient {
+            read,
+            write,
+            buf: Vec::new(),
+        }
+    }
+
+    pub async fn send(&mut self, msg: &Message) {
+        self.write_raw(&protocol::encode(msg)).await;
+    }
+
+    /// Sends bytes as they are, such as a frame the server should refuse.
+    pub async fn write_raw(&mut self, bytes: &[u8]) {
+        self.write.write_all(bytes).await.expect("should send");
+    }
+
+    /// The next message, or `None` once the server closes the connection.
+    pub async fn recv(&mut self) -> Option<Message> {
+        time::timeout(TIMEOUT, async {
+            loop {
+                match protocol::decode(&self.buf, DEFAULT_MAX_FRAME_LEN).expect("should decode") {
+                    Some((Message::Ping { token }, used)) => {
+                        self.buf.drain(..used);
+                        self.send(&Message::Pong { token }).await;
+                    }
+                    Some((msg, used)) => {
+                        self.buf.drain(..used);
+                        return Some(msg);
+                    }
+                    None => {
+                        let n = self
+                            .read
+                            .read_buf(&mut self.buf)
+                            .await
+                            .expect("should read");
+                        if n == 0 {
+                            assert!(self.buf.is_empty(), "connection closed mid-frame");
+                            return None;
+                        }
+                    }
+                }
+            }
+        })
+        .await
+        .expect("timed out waiting for a message")
+    }
+
+    /// Asserts the next message is `expected`.
+    pub async fn expect(&mut self, expected: Message) {
+        assert_eq!(self.recv().await, Some(expected));
+    }
+
+    /// Reads until a message satisfying `matches` arrives and returns it.
+    pub async fn skip_until(&mut self, matches: impl Fn(&Message) -> bool) -> Message {
+        loop {
+            match self.recv().await {
+                Some(msg) if matches(&msg) => return msg,
+                Some(_) => {}
+                None => panic!("connection closed before the expected message"),
+            }
+        }
+    }
+
+    /// Reads until the server closes the connection and returns the
+    /// messages received on the way.
+    pub async fn expect_closed(&mut self) -> Vec<Message> {
+        let mut msgs = Vec::new();
+        while let Some(msg) = self.recv().await {
+            msgs.push(msg);
+        }
+        msgs
+    }
+
+    /// Expects the greeting, nickname prompt and confirmation.
+    async fn expect_registered(&mut self, nick: &str) {
+        self.expect(Message::Hello { version: VERSION }).await;
+        self.expect(Message::system("welcome! choose a nickname:"))
+            .await;
+        self.expect(nick_request(nick)).await;
+    }
+
+    /// Joins `room` and waits for the server to confirm it.
+    pub async fn join(&mut self, room: &str) {
+        self.send(&Message::Join {
+            room: room.to_string(),
+            nick: None,
+            since: None,
+        })
+        .await;
+        self.expect(Message::Join {
+            room: room.to_string(),
+            nick: None,
+            since: None,
+        })
+        .await;
+    }
+
+    /// Sends and expects messages in order.
+    pub async fn run(&mut self, script: &[Step<Message>]) {
+        for step in script {
+            match step {
+                Step::Send(msg) => self.send(msg).await,
+                Step::Expect(msg) => self.expect(msg.clone()).await,
+            }
+        }
+    }
+}
+
+/// A WebSocket client, as a browser would be. Sends text frames and reads
+/// the server's JSON messages.
+pub struct WsClient {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl WsClient {
+    /// Connects without registering.
+    pub async fn connect(addr: SocketAddr) -> WsClient {
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
+            .await
+            .expect("should connect");
+        WsClient { ws }
+    }
+
+    /// Sends `text` as a text frame: a chat line, or a JSON [`Message`].
+    pub async fn send(&mut self, text: &str) {
+        self.ws
+            .send(WsMessage::text(text))
+            .await
+            .expect("should send");
+    }
+
+    /// The next message, or `None` once the server closes the connection.
+    pub async fn recv(&mut self) -> Option<Message> {
+        time::timeout(TIMEOUT, async {
+            loop {
+                match self.ws.next().await? {
+                    Ok(WsMessage::Text(text)) => {
+                        return Some(serde_json::from_str(&text).expect("should be a message"))
+                    }
+                    Ok(WsMessage::Close(_)) | Err(_) => return None,
+                    Ok(_) => {}
+                }
+            }
+        })
+        .await
+        .expect("timed out waiting for a message")
+    }
+
+    /// Asserts the next message is `expected`.
+    pub async fn expect(&mut self, expected: Message) {
+        assert_eq!(self.recv().await, Some(expected));
+    }
+
+    /// Reads until the server closes the connection and returns the
+    /// messages received on the way.
+    pub async fn expect_closed(&mut self) -> Vec<Message> {
+        let mut msgs = Vec::new();
+        while let Some(msg) = self.recv().await {
+            msgs.push(msg);
+        }
+        msgs
+    }
+
+    /// Expects the greeting, nickname prompt and confirmation.
+    async fn expect_registered(&mut self, nick: &str) {
+        self.expect(Message::Hello { version: VERSION }).await;
+        self.expect(Message::system("welcome! choose a nickname:"))
+            .await;
+        self.expect(nick_request(nick)).await;
+    }
+}